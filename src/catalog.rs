@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use datafusion::catalog::TableProvider;
+use datafusion::datasource::listing::ListingTable;
+use datafusion::datasource::{MemTable, TableType, ViewTable};
+use datafusion::error::Result;
+use datafusion::execution::SessionState;
+use datafusion::logical_expr::Signature;
+use datafusion::prelude::SessionContext;
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub(crate) struct SchemaEntry {
+    catalog: String,
+    name: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TableEntry {
+    catalog: String,
+    schema: String,
+    name: String,
+    /// `arrow`, `csv` or another file extension for tables backed by files,
+    /// otherwise `view`, `memory`, `temporary` or `table`
+    table_type: String,
+    /// Locations of the backing files, e.g. `opfs:///<digest>.arrow`
+    paths: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct FunctionEntry {
+    name: String,
+    /// `scalar`, `aggregate` or `window`
+    kind: &'static str,
+    aliases: Vec<String>,
+    /// Accepted argument types, one entry per variant of the signature
+    signatures: Vec<String>,
+}
+
+pub(crate) fn schemas(ctx: &SessionContext, catalog: Option<&str>) -> Vec<SchemaEntry> {
+    let mut entries = Vec::new();
+    for catalog_name in ctx.catalog_names() {
+        if catalog.is_some_and(|catalog| catalog != catalog_name) {
+            continue;
+        }
+        if let Some(catalog) = ctx.catalog(&catalog_name) {
+            for name in catalog.schema_names() {
+                entries.push(SchemaEntry {
+                    catalog: catalog_name.clone(),
+                    name,
+                });
+            }
+        }
+    }
+    entries
+}
+
+pub(crate) async fn tables(
+    ctx: &SessionContext,
+    catalog: Option<&str>,
+    schema: Option<&str>,
+) -> Result<Vec<TableEntry>> {
+    let mut entries = Vec::new();
+    for entry in schemas(ctx, catalog) {
+        if schema.is_some_and(|schema| schema != entry.name) {
+            continue;
+        }
+        let schema = match ctx
+            .catalog(&entry.catalog)
+            .and_then(|catalog| catalog.schema(&entry.name))
+        {
+            Some(schema) => schema,
+            None => continue,
+        };
+        let mut names = schema.table_names();
+        names.sort();
+        for name in names {
+            if let Some(table) = schema.table(&name).await? {
+                let (table_type, paths) = describe(&table);
+                entries.push(TableEntry {
+                    catalog: entry.catalog.clone(),
+                    schema: entry.name.clone(),
+                    name,
+                    table_type,
+                    paths,
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn describe(table: &Arc<dyn TableProvider>) -> (String, Vec<String>) {
+    let any = table.as_any();
+    if let Some(listing) = any.downcast_ref::<ListingTable>() {
+        let paths = listing
+            .table_paths()
+            .iter()
+            .map(|url| url.as_str().to_string())
+            .collect();
+        (listing.options().format.get_ext(), paths)
+    } else if any.is::<ViewTable>() {
+        ("view".to_string(), Vec::new())
+    } else if any.is::<MemTable>() {
+        ("memory".to_string(), Vec::new())
+    } else {
+        let table_type = match table.table_type() {
+            TableType::View => "view",
+            TableType::Temporary => "temporary",
+            TableType::Base => "table",
+        };
+        (table_type.to_string(), Vec::new())
+    }
+}
+
+pub(crate) fn functions(state: &SessionState) -> Vec<FunctionEntry> {
+    let scalar = state
+        .scalar_functions()
+        .values()
+        .map(|f| function("scalar", f.name(), f.aliases(), f.signature()));
+    let aggregate = state
+        .aggregate_functions()
+        .values()
+        .map(|f| function("aggregate", f.name(), f.aliases(), f.signature()));
+    let window = state
+        .window_functions()
+        .values()
+        .map(|f| function("window", f.name(), f.aliases(), f.signature()));
+    let mut entries: Vec<FunctionEntry> = scalar.chain(aggregate).chain(window).collect();
+    // the registries hold every function once per alias
+    entries.sort_by(|a, b| (a.kind, &a.name).cmp(&(b.kind, &b.name)));
+    entries.dedup_by(|a, b| a.kind == b.kind && a.name == b.name);
+    entries
+}
+
+fn function(
+    kind: &'static str,
+    name: &str,
+    aliases: &[String],
+    signature: &Signature,
+) -> FunctionEntry {
+    FunctionEntry {
+        name: name.to_string(),
+        kind,
+        aliases: aliases.to_vec(),
+        signatures: signature.type_signature.to_string_repr(),
+    }
+}
@@ -0,0 +1,399 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use datafusion::error::Result as DataFusionResult;
+use datafusion::execution::object_store::ObjectStoreRegistry;
+use datafusion::execution::runtime_env::RuntimeEnv;
+use datafusion::execution::TaskContext;
+use datafusion::physical_plan::ExecutionPlan;
+use futures::channel::oneshot;
+use futures::future::{select, Either};
+use futures::{Stream, StreamExt};
+use js_sys::{Function, Object, Promise, Reflect};
+use object_store::ObjectStore;
+use serde::Serialize;
+use url::Url;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::AbortSignal;
+
+use crate::error::{ErrorKind, QueryError};
+use crate::opfs_store::OpfsFileSystem;
+use crate::{_opfs_url, opfs_store};
+
+/// How long a query may keep the event loop busy before it lets the browser
+/// handle events again, otherwise an abort or a timeout could never fire
+const YIELD_INTERVAL_MS: f64 = 50.0;
+
+/// Time between two calls of the progress callback while a query runs
+const PROGRESS_INTERVAL_MS: u32 = 100;
+
+/// Options JS can pass along with a query,
+/// `{ signal?: AbortSignal, timeout?: number, onProgress?: (progress) => void }`
+#[derive(Default)]
+pub(crate) struct QueryControl {
+    signal: Option<AbortSignal>,
+    timeout: Option<u32>,
+    on_progress: Option<Function>,
+}
+
+impl QueryControl {
+    pub(crate) fn from_options(options: Option<Object>) -> Result<QueryControl, QueryError> {
+        let options = match options {
+            Some(options) => options,
+            None => return Ok(QueryControl::default()),
+        };
+        let signal =
+            Reflect::get(&options, &JsValue::from_str("signal")).map_err(invalid_options)?;
+        let signal = if signal.is_undefined() || signal.is_null() {
+            None
+        } else {
+            Some(signal.dyn_into::<AbortSignal>().map_err(|_| {
+                QueryError::new(
+                    ErrorKind::Parse,
+                    "Invalid options: signal is not an AbortSignal",
+                )
+            })?)
+        };
+        let timeout =
+            Reflect::get(&options, &JsValue::from_str("timeout")).map_err(invalid_options)?;
+        let timeout = if timeout.is_undefined() || timeout.is_null() {
+            None
+        } else {
+            match timeout.as_f64() {
+                Some(ms) if ms >= 0.0 => Some(ms as u32),
+                _ => {
+                    return Err(QueryError::new(
+                        ErrorKind::Parse,
+                        "Invalid options: timeout must be a positive number of milliseconds",
+                    ))
+                }
+            }
+        };
+        let on_progress =
+            Reflect::get(&options, &JsValue::from_str("onProgress")).map_err(invalid_options)?;
+        let on_progress = if on_progress.is_undefined() || on_progress.is_null() {
+            None
+        } else {
+            Some(on_progress.dyn_into::<Function>().map_err(|_| {
+                QueryError::new(
+                    ErrorKind::Parse,
+                    "Invalid options: onProgress is not a function",
+                )
+            })?)
+        };
+        Ok(QueryControl {
+            signal,
+            timeout,
+            on_progress,
+        })
+    }
+
+    /// Reporter for the execution of `plan`, if JS asked for progress updates
+    pub(crate) fn progress(&self, plan: Arc<dyn ExecutionPlan>) -> Option<ProgressReporter> {
+        let callback = self.on_progress.clone()?;
+        Some(ProgressReporter {
+            state: Rc::new(ProgressState {
+                callback,
+                plan,
+                started: js_sys::Date::now(),
+                bytes_read: Arc::new(AtomicU64::new(0)),
+                batches: Cell::new(0),
+            }),
+            timer: None,
+        })
+    }
+
+    /// Starts listening for the abort signal and the timeout, until the returned value is dropped
+    pub(crate) fn watch(&self) -> Result<Cancellation, QueryError> {
+        if let Some(signal) = &self.signal {
+            if signal.aborted() {
+                return Err(aborted(signal));
+            }
+        }
+        let (tx, rx) = oneshot::channel();
+        let tx = std::rc::Rc::new(std::cell::RefCell::new(Some(tx)));
+
+        let listener = self.signal.as_ref().map(|signal| {
+            let tx = tx.clone();
+            let target = signal.clone();
+            let on_abort = Closure::<dyn FnMut()>::new(move || {
+                if let Some(tx) = tx.borrow_mut().take() {
+                    let _ = tx.send(aborted(&target));
+                }
+            });
+            let _ =
+                signal.add_event_listener_with_callback("abort", on_abort.as_ref().unchecked_ref());
+            (signal.clone(), on_abort)
+        });
+
+        let timer = match self.timeout {
+            Some(ms) => {
+                let on_timeout = Closure::<dyn FnMut()>::new(move || {
+                    if let Some(tx) = tx.borrow_mut().take() {
+                        let message = format!("Query cancelled after the timeout of {ms} ms");
+                        let _ = tx.send(QueryError::new(ErrorKind::Cancelled, message));
+                    }
+                });
+                let id = global_fn("setTimeout")?
+                    .call2(&js_sys::global(), on_timeout.as_ref(), &JsValue::from(ms))
+                    .map_err(|e| QueryError::new(ErrorKind::Execution, format!("{e:?}")))?;
+                Some((id, on_timeout))
+            }
+            None => None,
+        };
+
+        Ok(Cancellation {
+            rx,
+            listener,
+            timer,
+        })
+    }
+}
+
+/// What the progress callback is called with
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Progress {
+    /// Rows produced by the scans at the leaves of the plan
+    rows_scanned: usize,
+    /// Bytes the query read from OPFS
+    bytes_read: u64,
+    /// Record batches the query returned so far
+    batches: usize,
+    elapsed_ms: f64,
+    done: bool,
+}
+
+struct ProgressState {
+    callback: Function,
+    plan: Arc<dyn ExecutionPlan>,
+    started: f64,
+    bytes_read: Arc<AtomicU64>,
+    batches: Cell<usize>,
+}
+
+impl ProgressState {
+    fn report(&self, done: bool) {
+        let progress = Progress {
+            rows_scanned: scanned_rows(self.plan.as_ref()),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            batches: self.batches.get(),
+            elapsed_ms: js_sys::Date::now() - self.started,
+            done,
+        };
+        // a failing callback must not fail the query
+        if let Ok(progress) = serde_wasm_bindgen::to_value(&progress) {
+            let _ = self.callback.call1(&JsValue::NULL, &progress);
+        }
+    }
+}
+
+/// Calls the progress callback of one query, the interval timer is cleared on drop
+pub(crate) struct ProgressReporter {
+    state: Rc<ProgressState>,
+    timer: Option<(JsValue, Closure<dyn FnMut()>)>,
+}
+
+impl ProgressReporter {
+    /// `task_ctx` reading OPFS through a store of its own, which counts the bytes read
+    /// by this query only
+    pub(crate) fn count_reads(&self, task_ctx: TaskContext) -> TaskContext {
+        let runtime = task_ctx.runtime_env();
+        let registry = CountingRegistry {
+            inner: Arc::clone(&runtime.object_store_registry),
+            opfs: Arc::new(opfs_store().counting(Arc::clone(&self.state.bytes_read))),
+        };
+        task_ctx.with_runtime(Arc::new(RuntimeEnv {
+            memory_pool: Arc::clone(&runtime.memory_pool),
+            disk_manager: Arc::clone(&runtime.disk_manager),
+            cache_manager: Arc::clone(&runtime.cache_manager),
+            object_store_registry: Arc::new(registry),
+        }))
+    }
+
+    /// Passes `stream` through, calling the callback every [`PROGRESS_INTERVAL_MS`] until
+    /// the stream ends and once more when it does. The timer fires whenever the query
+    /// waits for OPFS, so aggregates report before their first batch.
+    pub(crate) fn observe<S, T>(
+        mut self,
+        stream: S,
+    ) -> Result<impl Stream<Item = Result<T, QueryError>>, QueryError>
+    where
+        S: Stream<Item = Result<T, QueryError>> + Unpin,
+    {
+        let state = Rc::clone(&self.state);
+        let on_interval = Closure::<dyn FnMut()>::new(move || state.report(false));
+        let id = global_fn("setInterval")?
+            .call2(
+                &js_sys::global(),
+                on_interval.as_ref(),
+                &JsValue::from(PROGRESS_INTERVAL_MS),
+            )
+            .map_err(|e| QueryError::new(ErrorKind::Execution, format!("{e:?}")))?;
+        self.timer = Some((id, on_interval));
+
+        Ok(futures::stream::unfold(
+            (stream, Some(self)),
+            |(mut stream, reporter)| async move {
+                let reporter = reporter?;
+                match stream.next().await {
+                    Some(Ok(item)) => {
+                        let batches = &reporter.state.batches;
+                        batches.set(batches.get() + 1);
+                        Some((Ok(item), (stream, Some(reporter))))
+                    }
+                    Some(Err(error)) => Some((Err(error), (stream, None))),
+                    None => {
+                        let state = Rc::clone(&reporter.state);
+                        drop(reporter);
+                        state.report(true);
+                        None
+                    }
+                }
+            },
+        ))
+    }
+}
+
+impl Drop for ProgressReporter {
+    fn drop(&mut self) {
+        if let Some((id, _)) = &self.timer {
+            if let Ok(clear) = global_fn("clearInterval") {
+                let _ = clear.call1(&js_sys::global(), id);
+            }
+        }
+    }
+}
+
+fn scanned_rows(plan: &dyn ExecutionPlan) -> usize {
+    let children = plan.children();
+    if children.is_empty() {
+        plan.metrics()
+            .and_then(|metrics| metrics.output_rows())
+            .unwrap_or(0)
+    } else {
+        children
+            .into_iter()
+            .map(|child| scanned_rows(child.as_ref()))
+            .sum()
+    }
+}
+
+/// The object stores of a session, with OPFS replaced by a counting store
+#[derive(Debug)]
+struct CountingRegistry {
+    inner: Arc<dyn ObjectStoreRegistry>,
+    opfs: Arc<OpfsFileSystem>,
+}
+
+impl ObjectStoreRegistry for CountingRegistry {
+    fn register_store(
+        &self,
+        url: &Url,
+        store: Arc<dyn ObjectStore>,
+    ) -> Option<Arc<dyn ObjectStore>> {
+        self.inner.register_store(url, store)
+    }
+
+    fn get_store(&self, url: &Url) -> DataFusionResult<Arc<dyn ObjectStore>> {
+        if url.scheme() == _opfs_url().scheme() {
+            return Ok(Arc::clone(&self.opfs) as Arc<dyn ObjectStore>);
+        }
+        self.inner.get_store(url)
+    }
+}
+
+/// Listeners for one query, removed again on drop
+pub(crate) struct Cancellation {
+    rx: oneshot::Receiver<QueryError>,
+    listener: Option<(AbortSignal, Closure<dyn FnMut()>)>,
+    timer: Option<(JsValue, Closure<dyn FnMut()>)>,
+}
+
+impl Cancellation {
+    /// Wraps `stream` so it ends with a cancelled error as soon as the query is aborted
+    /// or times out. The inner stream is dropped then, which stops its execution.
+    pub(crate) fn guard<S, T>(self, stream: S) -> impl Stream<Item = Result<T, QueryError>>
+    where
+        S: Stream<Item = Result<T, QueryError>> + Unpin,
+    {
+        let state = (stream, Some(self), js_sys::Date::now());
+        futures::stream::unfold(state, |(mut stream, cancellation, last_yield)| async move {
+            let mut cancellation = cancellation?;
+            let mut last_yield = last_yield;
+            if js_sys::Date::now() - last_yield >= YIELD_INTERVAL_MS {
+                yield_to_event_loop().await;
+                last_yield = js_sys::Date::now();
+            }
+            // check the cancellation first, a busy stream is always ready
+            let next = match select(&mut cancellation.rx, stream.next()).await {
+                Either::Left((reason, _)) => Err(reason),
+                Either::Right((item, _)) => Ok(item),
+            };
+            match next {
+                Ok(Some(item)) => Some((item, (stream, Some(cancellation), last_yield))),
+                Ok(None) => None,
+                Err(Ok(error)) => Some((Err(error), (stream, None, last_yield))),
+                // the listeners own the sender, it can't be gone while they are registered
+                Err(Err(_)) => None,
+            }
+        })
+    }
+}
+
+impl Drop for Cancellation {
+    fn drop(&mut self) {
+        if let Some((signal, on_abort)) = &self.listener {
+            let _ = signal
+                .remove_event_listener_with_callback("abort", on_abort.as_ref().unchecked_ref());
+        }
+        if let Some((id, _)) = &self.timer {
+            if let Ok(clear) = global_fn("clearTimeout") {
+                let _ = clear.call1(&js_sys::global(), id);
+            }
+        }
+    }
+}
+
+fn aborted(signal: &AbortSignal) -> QueryError {
+    let reason = signal.reason();
+    let message = match reason.as_string() {
+        Some(reason) => format!("Query cancelled: {reason}"),
+        None => match Reflect::get(&reason, &JsValue::from_str("message"))
+            .ok()
+            .and_then(|m| m.as_string())
+        {
+            Some(reason) => format!("Query cancelled: {reason}"),
+            None => "Query cancelled".to_string(),
+        },
+    };
+    QueryError::new(ErrorKind::Cancelled, message)
+}
+
+fn invalid_options(error: JsValue) -> QueryError {
+    QueryError::new(ErrorKind::Parse, format!("Invalid options: {error:?}"))
+}
+
+// windows and workers both have setTimeout, but web_sys only binds it per global type
+fn global_fn(name: &str) -> Result<Function, QueryError> {
+    Reflect::get(&js_sys::global(), &JsValue::from_str(name))
+        .ok()
+        .and_then(|f| f.dyn_into::<Function>().ok())
+        .ok_or_else(|| QueryError::new(ErrorKind::Execution, format!("{name} is not available")))
+}
+
+/// Resolves after the browser had a chance to run pending tasks and events
+async fn yield_to_event_loop() {
+    let set_timeout = match global_fn("setTimeout") {
+        Ok(set_timeout) => set_timeout,
+        Err(_) => return,
+    };
+    let promise = Promise::new(&mut |resolve, _| {
+        let _ = set_timeout.call2(&js_sys::global(), &resolve, &JsValue::from(0));
+    });
+    let _ = JsFuture::from(promise).await;
+}
@@ -0,0 +1,337 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::Arc;
+
+use datafusion::arrow::array::{Array, ArrayRef, BooleanArray, StringArray, UInt64Array};
+use datafusion::arrow::compute::filter_record_batch;
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::error::ArrowError;
+use datafusion::arrow::record_batch::RecordBatch;
+use serde::Deserialize;
+
+use crate::csv_import::{ColumnFormats, CsvConfig};
+
+/// What an import does with rows it cannot read, `on_error` of a [`CsvConfig`]
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+    /// Fail the import
+    #[default]
+    Fail,
+    /// Leave the row out
+    Skip,
+    /// Import the row, with nulls for the values that cannot be read
+    NullFill,
+}
+
+/// A row left out, or filled with nulls, by an import
+struct RejectedRow {
+    line: u64,
+    /// `None` if the row has the wrong number of fields
+    column: Option<String>,
+    value: Option<String>,
+    reason: String,
+    record: String,
+}
+
+/// Schema of the `<name>.rejected.arrow` table written next to a tolerant import
+fn rejected_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("line", DataType::UInt64, false),
+        Field::new("column", DataType::Utf8, true),
+        Field::new("value", DataType::Utf8, true),
+        Field::new("reason", DataType::Utf8, false),
+        Field::new("record", DataType::Utf8, false),
+    ])
+}
+
+/// Follows the quotes of a CSV byte by byte the way the arrow decoder reads them. A
+/// quote only opens a field it starts, or goes on with a quoted field right after its
+/// closing quote as a doubled quote, anywhere else it is a literal character.
+struct Quoting {
+    quote: Option<u8>,
+    escape: Option<u8>,
+    quoted: bool,
+    escaped: bool,
+    field_start: bool,
+    /// The last byte closed a quoted field
+    closed: bool,
+}
+
+impl Quoting {
+    /// Reads `b`, true if it is neither inside a quoted field nor a quote
+    fn read(&mut self, b: u8) -> bool {
+        let opens =
+            std::mem::replace(&mut self.field_start, false) | std::mem::take(&mut self.closed);
+        if self.escaped {
+            self.escaped = false;
+        } else if self.quoted && Some(b) == self.escape {
+            self.escaped = true;
+        } else if Some(b) == self.quote && (self.quoted || opens) {
+            self.quoted = !self.quoted;
+            self.closed = !self.quoted;
+        } else {
+            return !self.quoted;
+        }
+        false
+    }
+
+    /// Starts a field, after a delimiter or a line break
+    fn next_field(&mut self) {
+        self.field_start = true;
+    }
+}
+
+/// Error handling of an import with `on_error` `skip` or `null_fill`. Splits the CSV
+/// into records itself to catch rows with the wrong number of fields before the arrow
+/// decoder fails on them, and converts the values, decoded as strings, to the schema.
+pub(crate) struct ErrorHandling {
+    on_error: OnError,
+    max_errors: Option<usize>,
+    delimiter: u8,
+    quote: Option<u8>,
+    escape: Option<u8>,
+    comment: Option<u8>,
+    /// Fields of every row, padding columns included
+    fields: usize,
+    truncated: bool,
+    /// Whether the next record is the header
+    header: bool,
+    /// Start of a record cut off at the end of the last chunk
+    rest: Vec<u8>,
+    /// Line of the next record, counted from 1
+    line: u64,
+    /// Lines of the rows handed to the decoder but not converted yet
+    lines: VecDeque<u64>,
+    rejected: Vec<RejectedRow>,
+}
+
+impl ErrorHandling {
+    /// `None` for `on_error` `fail`, the arrow decoder handles that on its own
+    pub fn new(cfg: &CsvConfig, fields: usize) -> Option<ErrorHandling> {
+        let byte = |s: &str| (s.len() == 1).then(|| s.as_bytes()[0]);
+        let quote = byte(&cfg.quote).or(Some(b'"'));
+        (cfg.on_error != OnError::Fail).then(|| ErrorHandling {
+            on_error: cfg.on_error,
+            max_errors: cfg.max_errors,
+            delimiter: byte(&cfg.delimiter).unwrap_or(b','),
+            quote,
+            // a doubled quote reads the same as an escaped one
+            escape: byte(&cfg.escape).filter(|escape| Some(*escape) != quote),
+            comment: byte(&cfg.comment),
+            fields,
+            truncated: cfg.truncated,
+            header: cfg.has_header.unwrap_or(true),
+            rest: Vec::new(),
+            line: 1,
+            lines: VecDeque::new(),
+            rejected: Vec::new(),
+        })
+    }
+
+    /// The records of `chunk` the decoder can read, `last` if it ends the file. Rows with
+    /// the wrong number of fields are left out, or with `null_fill` padded or cut.
+    pub fn records(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, ArrowError> {
+        let mut data = std::mem::take(&mut self.rest);
+        data.extend_from_slice(chunk);
+        let mut records = Vec::with_capacity(data.len());
+        let mut start = 0;
+        let mut quoting = self.quoting();
+        let mut fields = 1;
+        let mut newlines = 0;
+        for (i, b) in data.iter().enumerate() {
+            let unquoted = quoting.read(*b);
+            if *b == self.delimiter && unquoted {
+                fields += 1;
+                quoting.next_field();
+            } else if *b == b'\n' && !unquoted {
+                newlines += 1;
+            } else if *b == b'\n' {
+                self.record(&data[start..i], fields, &mut records)?;
+                self.line += newlines + 1;
+                start = i + 1;
+                fields = 1;
+                newlines = 0;
+                quoting.next_field();
+            }
+        }
+        if !last {
+            self.rest = data[start..].to_vec();
+        } else if start < data.len() {
+            self.record(&data[start..], fields, &mut records)?;
+        }
+        Ok(records)
+    }
+
+    fn record(
+        &mut self,
+        record: &[u8],
+        fields: usize,
+        records: &mut Vec<u8>,
+    ) -> Result<(), ArrowError> {
+        let record = record.strip_suffix(b"\r").unwrap_or(record);
+        // the decoder skips these as well
+        if record.is_empty() || record.first().is_some_and(|b| Some(*b) == self.comment) {
+            return Ok(());
+        }
+        if std::mem::take(&mut self.header) {
+            records.extend_from_slice(record);
+            records.push(b'\n');
+            return Ok(());
+        }
+        let line = self.line;
+        if fields == self.fields || (self.truncated && fields < self.fields) {
+            self.push(record, line, records);
+            return Ok(());
+        }
+        self.reject(RejectedRow {
+            line,
+            column: None,
+            value: None,
+            reason: format!("Expected {} fields, found {fields}", self.fields),
+            record: String::from_utf8_lossy(record).into_owned(),
+        })?;
+        if self.on_error == OnError::NullFill {
+            if fields < self.fields {
+                let mut filled = record.to_vec();
+                filled.resize(record.len() + self.fields - fields, self.delimiter);
+                self.push(&filled, line, records);
+            } else {
+                let end = self.fields_end(record);
+                self.push(&record[..end], line, records);
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, record: &[u8], line: u64, records: &mut Vec<u8>) {
+        records.extend_from_slice(record);
+        records.push(b'\n');
+        self.lines.push_back(line);
+    }
+
+    /// End of the last field of `record` that is part of the table
+    fn fields_end(&self, record: &[u8]) -> usize {
+        let mut quoting = self.quoting();
+        let mut fields = 1;
+        for (i, b) in record.iter().enumerate() {
+            if quoting.read(*b) && *b == self.delimiter {
+                if fields == self.fields {
+                    return i;
+                }
+                fields += 1;
+                quoting.next_field();
+            }
+        }
+        record.len()
+    }
+
+    fn quoting(&self) -> Quoting {
+        Quoting {
+            quote: self.quote,
+            escape: self.escape,
+            quoted: false,
+            escaped: false,
+            field_start: true,
+            closed: false,
+        }
+    }
+
+    fn reject(&mut self, row: RejectedRow) -> Result<(), ArrowError> {
+        let error = format!("line {}: {}", row.line, row.reason);
+        self.rejected.push(row);
+        if self.exceeded() {
+            return Err(ArrowError::ParseError(format!(
+                "More than {} errors in the CSV, the last in {error}",
+                self.max_errors.unwrap_or_default()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether the import failed because of too many errors
+    pub fn exceeded(&self) -> bool {
+        self.max_errors.is_some_and(|max| self.rejected.len() > max)
+    }
+
+    /// Number of errors so far
+    pub fn errors(&self) -> usize {
+        self.rejected.len()
+    }
+
+    /// Converts a batch decoded with every column as string to `target`. With `skip` the
+    /// rows holding a value that does not parse are left out, with `null_fill` the value
+    /// becomes null.
+    pub fn batch(
+        &mut self,
+        strings: RecordBatch,
+        target: &SchemaRef,
+        formats: &ColumnFormats,
+    ) -> Result<RecordBatch, ArrowError> {
+        let rows = strings.num_rows().min(self.lines.len());
+        let lines: Vec<u64> = self.lines.drain(..rows).collect();
+        let (batch, errors) = formats.convert(&strings, target, 0)?;
+        let mut failed = BTreeSet::new();
+        for error in errors {
+            let row = error.row.unwrap_or_default();
+            failed.insert(row);
+            self.reject(RejectedRow {
+                line: lines.get(row).copied().unwrap_or_default(),
+                column: error.column,
+                value: error.value,
+                reason: error.message,
+                record: self.join(&strings, row),
+            })?;
+        }
+        if self.on_error == OnError::Skip && !failed.is_empty() {
+            let keep: BooleanArray = (0..batch.num_rows())
+                .map(|row| Some(!failed.contains(&row)))
+                .collect();
+            return filter_record_batch(&batch, &keep);
+        }
+        Ok(batch)
+    }
+
+    /// The values of `row`, separated by the delimiter
+    fn join(&self, strings: &RecordBatch, row: usize) -> String {
+        strings
+            .columns()
+            .iter()
+            .map(
+                |column| match column.as_any().downcast_ref::<StringArray>() {
+                    Some(values) if values.is_valid(row) => values.value(row),
+                    _ => "",
+                },
+            )
+            .collect::<Vec<_>>()
+            .join(&char::from(self.delimiter).to_string())
+    }
+
+    /// The errors so far as a batch of [`rejected_schema`]
+    pub fn rejected_batch(&self) -> Result<RecordBatch, ArrowError> {
+        let rejected = &self.rejected;
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt64Array::from_iter_values(
+                rejected.iter().map(|r| r.line),
+            )),
+            Arc::new(
+                rejected
+                    .iter()
+                    .map(|r| r.column.as_deref())
+                    .collect::<StringArray>(),
+            ),
+            Arc::new(
+                rejected
+                    .iter()
+                    .map(|r| r.value.as_deref())
+                    .collect::<StringArray>(),
+            ),
+            Arc::new(StringArray::from_iter_values(
+                rejected.iter().map(|r| &r.reason),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                rejected.iter().map(|r| &r.record),
+            )),
+        ];
+        RecordBatch::try_new(Arc::new(rejected_schema()), columns)
+    }
+}
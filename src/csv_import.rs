@@ -0,0 +1,956 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use datafusion::arrow::array::{
+    Array, ArrayRef, Date32Array, StringArray, TimestampNanosecondArray,
+};
+use datafusion::arrow::compute::{cast, cast_with_options, CastOptions};
+use datafusion::arrow::csv::reader::{Decoder, Format};
+use datafusion::arrow::csv::ReaderBuilder;
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use datafusion::arrow::error::ArrowError;
+use datafusion::arrow::ipc::writer::{FileWriter, IpcWriteOptions, StreamWriter};
+use datafusion::arrow::ipc::MetadataVersion;
+use datafusion::arrow::record_batch::RecordBatch;
+use futures::{stream, Stream, StreamExt};
+use js_sys::{ArrayBuffer, Uint8Array};
+use object_store::path::Path;
+use object_store::PutPayload;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize, Serializer};
+use wasm_bindgen::JsValue;
+
+use crate::csv_errors::{ErrorHandling, OnError};
+use crate::csv_sniff::{sniff, Encoding};
+use crate::opfs_store::OpfsError;
+use crate::schema::TableSchema;
+use crate::web_fs_utils::{CsvSource, FileWriterState};
+
+/// Rows used for schema inference unless the config says otherwise
+pub(crate) const DEFAULT_INFER_ROWS: usize = 1000;
+
+/// Settings of a CSV import, the empty ones are filled with the sniffed dialect
+#[derive(Deserialize, Default)]
+pub struct CsvConfig {
+    #[serde(default)]
+    pub delimiter: String,
+    #[serde(default)]
+    pub quote: String,
+    #[serde(default)]
+    pub comment: String,
+    #[serde(default)]
+    pub escape: String,
+    #[serde(default)]
+    pub null_regex: String,
+    #[serde(default)]
+    pub truncated: bool,
+    /// Whether the first row names the columns, sniffed if missing
+    #[serde(default)]
+    pub has_header: Option<bool>,
+    /// `utf-8`, `utf-16le`, `utf-16be` or `windows-1252`, sniffed if empty
+    #[serde(default)]
+    pub encoding: String,
+    /// Decimal separator of numbers, `.` or `,`, sniffed if empty
+    #[serde(default)]
+    pub decimal: String,
+    /// Drop the columns at the end that are empty in the sniffed rows, off if missing. The
+    /// import fails if one of them holds a value further down.
+    #[serde(default)]
+    pub trim_empty_columns: Option<bool>,
+    /// Rows the schema is inferred from, `"all"` scans the whole file
+    #[serde(default)]
+    pub infer_rows: Option<InferRows>,
+    /// Columns of the file in order, replaces schema inference
+    #[serde(default)]
+    pub schema: Option<Vec<ColumnType>>,
+    /// Types of single columns by name, applied to the inferred schema
+    #[serde(default)]
+    pub column_types: HashMap<String, String>,
+    /// chrono format of timestamp columns, e.g. `%d.%m.%Y %H:%M`
+    #[serde(default)]
+    pub timestamp_format: Option<String>,
+    /// chrono format of date columns, e.g. `%d.%m.%Y`
+    #[serde(default)]
+    pub date_format: Option<String>,
+    /// `fail`, `skip` or `null_fill`, the last two write the rows that cannot be read
+    /// to `<name>.rejected.arrow`
+    #[serde(default)]
+    pub on_error: OnError,
+    /// Errors `skip` and `null_fill` accept before the import fails
+    #[serde(default)]
+    pub max_errors: Option<usize>,
+}
+
+/// `infer_rows` of a [`CsvConfig`], a number of rows or `"all"` for a full scan
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum InferRows {
+    Rows(usize),
+    Keyword(String),
+}
+
+/// Column of an explicit schema, `{"name": "temp", "type": "Float64", "nullable": true}`
+#[derive(Deserialize, Clone)]
+pub struct ColumnType {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub data_type: String,
+    #[serde(default = "default_nullable")]
+    pub nullable: bool,
+}
+
+fn default_nullable() -> bool {
+    true
+}
+
+impl CsvConfig {
+    /// Rows to infer the schema from, `None` for all of them
+    pub(crate) fn infer_limit(&self) -> Result<Option<usize>, ArrowError> {
+        match &self.infer_rows {
+            None => Ok(Some(DEFAULT_INFER_ROWS)),
+            Some(InferRows::Rows(rows)) => Ok(Some(*rows)),
+            Some(InferRows::Keyword(keyword)) if keyword == "all" => Ok(None),
+            Some(InferRows::Keyword(keyword)) => Err(ArrowError::InvalidArgumentError(format!(
+                "infer_rows must be a number or \"all\", not \"{keyword}\""
+            ))),
+        }
+    }
+
+    /// Whether the schema comes from the config, so nothing needs to be inferred
+    pub(crate) fn has_explicit_schema(&self) -> bool {
+        self.schema
+            .as_ref()
+            .is_some_and(|columns| !columns.is_empty())
+    }
+}
+
+/// Parses a type name, either the Arrow notation like `Timestamp(Millisecond, None)`
+/// or one of the short names `string`, `int`, `float`, `bool`, `date` and `timestamp`
+pub(crate) fn parse_data_type(name: &str) -> Result<DataType, ArrowError> {
+    let data_type = match name.to_ascii_lowercase().as_str() {
+        "string" | "text" | "varchar" => DataType::Utf8,
+        "int" | "integer" | "bigint" => DataType::Int64,
+        "float" | "double" => DataType::Float64,
+        "bool" | "boolean" => DataType::Boolean,
+        "date" => DataType::Date32,
+        "timestamp" | "datetime" => DataType::Timestamp(TimeUnit::Millisecond, None),
+        _ => DataType::from_str(name)
+            .map_err(|_| ArrowError::InvalidArgumentError(format!("Unknown column type {name}")))?,
+    };
+    Ok(data_type)
+}
+
+/// The schema the import writes: the explicit schema of `cfg` if there is one,
+/// otherwise `inferred` with the per column overrides applied
+pub(crate) fn resolve_schema(inferred: Schema, cfg: &CsvConfig) -> Result<Schema, ArrowError> {
+    if let Some(columns) = cfg.schema.as_ref().filter(|columns| !columns.is_empty()) {
+        let fields = columns
+            .iter()
+            .map(|column| {
+                Ok(Field::new(
+                    &column.name,
+                    parse_data_type(&column.data_type)?,
+                    column.nullable,
+                ))
+            })
+            .collect::<Result<Vec<_>, ArrowError>>()?;
+        return Ok(Schema::new(fields));
+    }
+    for name in cfg.column_types.keys() {
+        if inferred.index_of(name).is_err() {
+            return Err(ArrowError::SchemaError(format!(
+                "Type override for unknown column {name}"
+            )));
+        }
+    }
+    let fields = inferred
+        .fields()
+        .iter()
+        .map(|field| match cfg.column_types.get(field.name()) {
+            Some(data_type) => Ok(field
+                .as_ref()
+                .clone()
+                .with_data_type(parse_data_type(data_type)?)),
+            None => Ok(field.as_ref().clone()),
+        })
+        .collect::<Result<Vec<_>, ArrowError>>()?;
+    Ok(Schema::new_with_metadata(
+        fields,
+        inferred.metadata().clone(),
+    ))
+}
+
+/// Widens the column types of `schema` with the types inferred from more rows,
+/// columns are matched by position as only the first chunk has the header
+pub(crate) fn merge_inferred(schema: Schema, more: &Schema) -> Schema {
+    let fields = schema
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match more.fields().get(i) {
+            Some(other) => {
+                let data_type = widen(field.data_type(), other.data_type());
+                field.as_ref().clone().with_data_type(data_type)
+            }
+            None => field.as_ref().clone(),
+        })
+        .collect::<Vec<_>>();
+    Schema::new(fields)
+}
+
+fn widen(a: &DataType, b: &DataType) -> DataType {
+    match (a, b) {
+        _ if a == b => a.clone(),
+        (DataType::Null, other) | (other, DataType::Null) => other.clone(),
+        (DataType::Int64, DataType::Float64) | (DataType::Float64, DataType::Int64) => {
+            DataType::Float64
+        }
+        (DataType::Date32, DataType::Timestamp(..)) => b.clone(),
+        (DataType::Timestamp(..), DataType::Date32) => a.clone(),
+        _ => DataType::Utf8,
+    }
+}
+
+/// With a decimal comma, columns inferred as strings that hold nothing but numbers like
+/// `1.234,5` are floats. `sample` holds the first rows with every column as string.
+pub(crate) fn comma_decimals(inferred: Schema, sample: &RecordBatch) -> Schema {
+    let fields = inferred
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            let strings = match sample
+                .columns()
+                .get(i)
+                .and_then(|c| c.as_any().downcast_ref::<StringArray>())
+            {
+                Some(strings) if field.data_type() == &DataType::Utf8 => strings,
+                _ => return field.as_ref().clone(),
+            };
+            let mut values = strings.iter().flatten().peekable();
+            if values.peek().is_some() && values.all(|value| COMMA_NUMBER.is_match(value.trim())) {
+                field.as_ref().clone().with_data_type(DataType::Float64)
+            } else {
+                field.as_ref().clone()
+            }
+        })
+        .collect::<Vec<_>>();
+    Schema::new_with_metadata(fields, inferred.metadata().clone())
+}
+
+static COMMA_NUMBER: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^[+-]?(\d{1,3}(\.\d{3})+|\d+)(,\d+)?$").unwrap());
+
+/// How a column that the arrow decoder cannot read is parsed from strings
+enum ColumnFormat {
+    /// chrono format of a date or timestamp column, the decoder only knows ISO 8601
+    Chrono(String),
+    /// Numbers like `1.234,5`
+    DecimalComma,
+}
+
+impl ColumnFormat {
+    fn describe(&self, data_type: &DataType) -> String {
+        match self {
+            ColumnFormat::Chrono(format) => format!("the format \"{format}\""),
+            ColumnFormat::DecimalComma => format!("{data_type} with decimal comma"),
+        }
+    }
+}
+
+/// Parses the columns with a custom date or timestamp format, or numbers with a decimal
+/// comma, as set in `cfg`. These columns are decoded as strings first.
+pub(crate) struct ColumnFormats {
+    /// Target type and format by column index
+    formats: HashMap<usize, (DataType, ColumnFormat)>,
+}
+
+impl ColumnFormats {
+    pub fn new(target: &Schema, cfg: &CsvConfig) -> ColumnFormats {
+        let formats = target
+            .fields()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, field)| {
+                let format = match field.data_type() {
+                    DataType::Timestamp(..) => ColumnFormat::Chrono(cfg.timestamp_format.clone()?),
+                    DataType::Date32 | DataType::Date64 => {
+                        ColumnFormat::Chrono(cfg.date_format.clone()?)
+                    }
+                    data_type
+                        if cfg.decimal == ","
+                            && (data_type.is_floating() || is_decimal(data_type)) =>
+                    {
+                        ColumnFormat::DecimalComma
+                    }
+                    _ => return None,
+                };
+                Some((i, (field.data_type().clone(), format)))
+            })
+            .collect();
+        ColumnFormats { formats }
+    }
+
+    /// The schema the CSV decoder works with
+    pub fn decode_schema(&self, target: &Schema) -> Schema {
+        let mut fields: Vec<Field> = target.fields().iter().map(|f| f.as_ref().clone()).collect();
+        for i in self.formats.keys() {
+            fields[*i] = fields[*i].clone().with_data_type(DataType::Utf8);
+        }
+        Schema::new_with_metadata(fields, target.metadata().clone())
+    }
+
+    /// Converts a batch of the decode schema to the `target` schema
+    pub fn apply(&self, batch: RecordBatch, target: &SchemaRef) -> Result<RecordBatch, ArrowError> {
+        if self.formats.is_empty() {
+            return Ok(batch);
+        }
+        let mut columns: Vec<ArrayRef> = batch.columns().to_vec();
+        for (i, (data_type, format)) in &self.formats {
+            let strings = as_strings(&columns[*i])?;
+            let parsed = parse_column(strings, data_type, format)?;
+            if let Some(row) = unparsed(strings, &parsed).next() {
+                return Err(ArrowError::ParseError(format!(
+                    "Cannot parse \"{}\" in column {} as {}",
+                    strings.value(row),
+                    target.field(*i).name(),
+                    format.describe(data_type)
+                )));
+            }
+            columns[*i] = parsed;
+        }
+        RecordBatch::try_new(Arc::clone(target), columns)
+    }
+
+    /// Converts a batch decoded with every column as string to the `target` schema.
+    /// Values that do not parse become null and are returned as errors, numbered from
+    /// `first_row` on.
+    pub fn convert(
+        &self,
+        batch: &RecordBatch,
+        target: &SchemaRef,
+        first_row: usize,
+    ) -> Result<(RecordBatch, Vec<CellError>), ArrowError> {
+        let mut columns = Vec::with_capacity(batch.num_columns());
+        let mut errors = Vec::new();
+        for (i, field) in target.fields().iter().enumerate() {
+            let strings = as_strings(batch.column(i))?;
+            let converted = match self.formats.get(&i) {
+                Some((data_type, format)) => parse_column(strings, data_type, format)?,
+                None => cast_with_options(strings, field.data_type(), &CastOptions::default())?,
+            };
+            for row in unparsed(strings, &converted) {
+                let expected = match self.formats.get(&i) {
+                    Some((data_type, format)) => format.describe(data_type),
+                    None => field.data_type().to_string(),
+                };
+                errors.push(CellError {
+                    row: Some(first_row + row),
+                    column: Some(field.name().clone()),
+                    value: Some(strings.value(row).to_string()),
+                    message: format!("Cannot parse \"{}\" as {expected}", strings.value(row)),
+                });
+            }
+            columns.push(converted);
+        }
+        errors.sort_by_key(|error| error.row);
+        Ok((RecordBatch::try_new(Arc::clone(target), columns)?, errors))
+    }
+}
+
+/// Outcome of a CSV import, see `load_csv_bytes`
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CsvImport {
+    pub(crate) schema: TableSchema,
+    /// Rows written to the table
+    pub(crate) rows: usize,
+    /// Rows left out or values set to null, see `on_error` of [`CsvConfig`]
+    pub(crate) errors: usize,
+    /// Name of the rejected rows file for `register_table`, only with `skip` and `null_fill`
+    pub(crate) rejected_table: Option<String>,
+}
+
+/// First rows of a CSV file, see `preview_csv`
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CsvPreview {
+    pub schema: TableSchema,
+    /// Arrow IPC stream of the rows, a `Uint8Array` in JS
+    #[serde(serialize_with = "as_bytes")]
+    pub rows: Vec<u8>,
+    /// Empty values per column, values that failed to parse are not counted
+    pub null_counts: BTreeMap<String, usize>,
+    pub errors: Vec<CellError>,
+}
+
+fn as_bytes<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bytes(bytes)
+}
+
+/// A value, or a whole row, of a CSV file that could not be read
+#[derive(Serialize)]
+pub(crate) struct CellError {
+    /// Index among the data rows, `None` if it is not known
+    pub row: Option<usize>,
+    /// `None` if the row could not be split into its fields
+    pub column: Option<String>,
+    pub value: Option<String>,
+    pub message: String,
+}
+
+impl CellError {
+    pub fn row(error: &ArrowError) -> CellError {
+        CellError {
+            row: None,
+            column: None,
+            value: None,
+            message: error.to_string(),
+        }
+    }
+}
+
+fn as_strings(column: &ArrayRef) -> Result<&StringArray, ArrowError> {
+    column
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| ArrowError::CastError("Column was not decoded as string".to_string()))
+}
+
+/// Rows that have a value in `strings` but not in `converted`
+fn unparsed<'a>(
+    strings: &'a StringArray,
+    converted: &'a ArrayRef,
+) -> impl Iterator<Item = usize> + 'a {
+    (0..strings.len()).filter(move |row| strings.is_valid(*row) && converted.is_null(*row))
+}
+
+fn is_decimal(data_type: &DataType) -> bool {
+    matches!(
+        data_type,
+        DataType::Decimal128(..) | DataType::Decimal256(..)
+    )
+}
+
+/// Parses `strings` with `format`, values that do not match become null
+fn parse_column(
+    strings: &StringArray,
+    data_type: &DataType,
+    format: &ColumnFormat,
+) -> Result<ArrayRef, ArrowError> {
+    let format = match format {
+        ColumnFormat::Chrono(format) => format,
+        ColumnFormat::DecimalComma => {
+            let numbers: StringArray = strings
+                .iter()
+                .map(|value| value.map(|value| value.trim().replace('.', "").replace(',', ".")))
+                .collect();
+            return cast_with_options(&numbers, data_type, &CastOptions::default());
+        }
+    };
+    let values = strings
+        .iter()
+        .map(|value| value.map(str::trim).filter(|value| !value.is_empty()));
+    let parsed: ArrayRef = match data_type {
+        DataType::Timestamp(..) => Arc::new(
+            values
+                .map(|value| {
+                    NaiveDateTime::parse_from_str(value?, format)
+                        .ok()?
+                        .and_utc()
+                        .timestamp_nanos_opt()
+                })
+                .collect::<TimestampNanosecondArray>(),
+        ),
+        _ => {
+            let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap_or_default();
+            Arc::new(
+                values
+                    .map(|value| {
+                        let date = NaiveDate::parse_from_str(value?, format).ok()?;
+                        Some((date - epoch).num_days() as i32)
+                    })
+                    .collect::<Date32Array>(),
+            )
+        }
+    };
+    cast(&parsed, data_type)
+}
+
+/// Bytes read from the source per step of a CSV import
+const CSV_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// The CSV dialect described by `cfg`
+fn csv_format(cfg: &CsvConfig) -> Result<Format, ArrowError> {
+    let delimiter = if cfg.delimiter.len() == 1 {
+        cfg.delimiter.as_bytes()[0]
+    } else {
+        b','
+    };
+
+    let mut csv_format = Format::default()
+        .with_header(cfg.has_header.unwrap_or(true))
+        .with_delimiter(delimiter)
+        .with_truncated_rows(cfg.truncated);
+
+    if cfg.quote.len() == 1 {
+        csv_format = csv_format.with_quote(cfg.quote.as_bytes()[0]);
+    }
+    if cfg.comment.len() == 1 {
+        csv_format = csv_format.with_comment(cfg.comment.as_bytes()[0]);
+    }
+    if cfg.escape.len() == 1 {
+        csv_format = csv_format.with_escape(cfg.escape.as_bytes()[0]);
+    }
+    if !cfg.null_regex.is_empty() && cfg.null_regex.len() <= 32 {
+        let null_regex = Regex::new(&cfg.null_regex)
+            .map_err(|e| ArrowError::InvalidArgumentError(format!("Invalid null regex: {e}")))?;
+        csv_format = csv_format.with_null_regex(null_regex);
+    }
+    Ok(csv_format)
+}
+
+/// A CSV source together with its config, completed by the sniffed dialect, and the
+/// schema it is read with
+struct CsvRead {
+    source: CsvSource,
+    cfg: CsvConfig,
+    encoding: Encoding,
+    format: Format,
+    schema: SchemaRef,
+    /// Empty columns at the end of every row that are not part of `schema`
+    padding: usize,
+    /// First chunk of the source, converted to UTF-8
+    first: Vec<u8>,
+    /// End of the bytes of the source converted into `first`
+    first_end: u64,
+    location: Path,
+}
+
+impl CsvRead {
+    /// Reads the first chunk of `source`, fills the empty fields of the config with the
+    /// dialect sniffed from it and determines the schema
+    async fn open(
+        source: CsvSource,
+        csv_config: JsValue,
+        location: Path,
+    ) -> Result<CsvRead, ArrowError> {
+        let cfg: CsvConfig = serde_wasm_bindgen::from_value(csv_config)
+            .map_err(|e| ArrowError::InvalidArgumentError(format!("Invalid csv config: {e}")))?;
+        let len = source.len();
+        let first = source
+            .read(0, len.min(CSV_CHUNK_SIZE), &location)
+            .await
+            .map_err(io_error)?;
+        let dialect = sniff(&first, first.len() as u64 == len);
+        let cfg = cfg.or_dialect(&dialect);
+        let encoding = Encoding::from_label(&cfg.encoding)?;
+        let last = first.len() as u64 == len;
+        let (first, first_end) = encoding.decode(first, true, last);
+        let mut read = CsvRead {
+            format: csv_format(&cfg)?,
+            first,
+            first_end: first_end as u64,
+            source,
+            cfg,
+            encoding,
+            schema: Arc::new(Schema::empty()),
+            padding: 0,
+            location,
+        };
+
+        let trim = if read.cfg.trim_empty_columns == Some(true) {
+            dialect.trailing_empty_columns
+        } else {
+            0
+        };
+        let inferred = if read.cfg.has_explicit_schema() {
+            Schema::empty()
+        } else {
+            let inferred = read.infer_schema(read.cfg.infer_limit()?).await?;
+            let width = inferred.fields().len();
+            let trimmed = Schema::new(inferred.fields()[..width.saturating_sub(trim)].to_vec());
+            match read.cfg.decimal.as_str() {
+                "," => comma_decimals(trimmed, &read.sample_strings(width)),
+                _ => trimmed,
+            }
+        };
+        let schema = resolve_schema(inferred, &read.cfg)?;
+        read.padding = dialect
+            .columns
+            .saturating_sub(schema.fields().len())
+            .min(trim);
+        read.schema = Arc::new(schema);
+        Ok(read)
+    }
+
+    fn len(&self) -> u64 {
+        self.source.len()
+    }
+
+    /// Reads the chunk starting at `offset`, converted to UTF-8, and returns it with the
+    /// offset of the next one
+    async fn chunk(&self, offset: u64) -> Result<(Vec<u8>, u64), ArrowError> {
+        let end = self.len().min(offset + CSV_CHUNK_SIZE);
+        let bytes = self
+            .source
+            .read(offset, end, &self.location)
+            .await
+            .map_err(io_error)?;
+        let (chunk, consumed) = self.encoding.decode(bytes, false, end >= self.len());
+        Ok((chunk, offset + consumed as u64))
+    }
+
+    /// Infers the schema from the first `limit` rows, or all rows if there is no limit.
+    /// Starts with the first chunk and only reads on if it holds too few rows.
+    async fn infer_schema(&self, limit: Option<usize>) -> Result<Schema, ArrowError> {
+        let mut offset = self.first_end;
+        // rows of the wrong length fail the import later on, unless they are tolerated
+        let tolerant = self.cfg.truncated || self.cfg.on_error != OnError::Fail;
+        let format = self.format.clone().with_truncated_rows(tolerant);
+        // a row cut off at the end of a chunk would skew the inferred types
+        let (sample, mut rest) = split_complete_rows(&self.first, offset >= self.len());
+        let (mut schema, read) = format.infer_schema(Cursor::new(sample), limit)?;
+        let mut remaining = limit.map(|limit| limit.saturating_sub(read));
+        // only the first chunk starts with the header
+        let format = format.with_header(false);
+        while offset < self.len() && remaining != Some(0) {
+            let (chunk, end) = self.chunk(offset).await?;
+            rest.extend(chunk);
+            offset = end;
+            let (sample, tail) = split_complete_rows(&rest, offset >= self.len());
+            let (more, read) = format.infer_schema(Cursor::new(sample), remaining)?;
+            schema = merge_inferred(schema, &more);
+            remaining = remaining.map(|remaining| remaining.saturating_sub(read));
+            rest = tail;
+        }
+        Ok(schema)
+    }
+
+    /// The complete rows of the first chunk, up to [`DEFAULT_INFER_ROWS`], with `width`
+    /// string columns. Empty if they cannot be read, the import reports the error.
+    fn sample_strings(&self, width: usize) -> RecordBatch {
+        let schema = Arc::new(Schema::new(
+            (0..width)
+                .map(|i| Field::new(format!("column_{i}"), DataType::Utf8, true))
+                .collect::<Vec<_>>(),
+        ));
+        let (sample, _) = split_complete_rows(&self.first, self.first_end >= self.len());
+        ReaderBuilder::new(Arc::clone(&schema))
+            .with_format(self.format.clone().with_truncated_rows(true))
+            .with_batch_size(DEFAULT_INFER_ROWS)
+            .build(Cursor::new(sample))
+            .ok()
+            .and_then(|mut reader| reader.next())
+            .and_then(Result::ok)
+            .unwrap_or_else(|| RecordBatch::new_empty(schema))
+    }
+
+    /// Decoder of rows with the fields of `schema`, followed by the padding columns
+    fn decoder(&self, schema: &Schema, batch_size: Option<usize>) -> PaddedDecoder {
+        let width = schema.fields().len();
+        let mut fields: Vec<Field> = schema.fields().iter().map(|f| f.as_ref().clone()).collect();
+        fields.extend(
+            (0..self.padding).map(|i| Field::new(format!("padding_{i}"), DataType::Utf8, true)),
+        );
+        let mut builder = ReaderBuilder::new(Arc::new(Schema::new_with_metadata(
+            fields,
+            schema.metadata().clone(),
+        )))
+        .with_format(self.format.clone());
+        if let Some(batch_size) = batch_size {
+            builder = builder.with_batch_size(batch_size);
+        }
+        PaddedDecoder {
+            decoder: builder.build_decoder(),
+            width,
+        }
+    }
+}
+
+/// CSV decoder that checks the padding columns after the first `width` columns are empty
+/// before it drops them, the sniffed rows say nothing about the rest of the file
+struct PaddedDecoder {
+    decoder: Decoder,
+    width: usize,
+}
+
+impl PaddedDecoder {
+    fn decode(&mut self, buf: &[u8]) -> Result<usize, ArrowError> {
+        self.decoder.decode(buf)
+    }
+
+    fn capacity(&self) -> usize {
+        self.decoder.capacity()
+    }
+
+    /// The rows decoded so far without the padding columns
+    fn flush(&mut self) -> Result<Option<RecordBatch>, ArrowError> {
+        let batch = match self.decoder.flush()? {
+            Some(batch) => batch,
+            None => return Ok(None),
+        };
+        for (i, column) in batch.columns().iter().enumerate().skip(self.width) {
+            let value = as_strings(column)?
+                .iter()
+                .flatten()
+                .find(|value| !value.trim().is_empty());
+            if let Some(value) = value {
+                return Err(ArrowError::CsvError(format!(
+                    "Column {} was trimmed as empty but holds {value:?}, \
+                     import with trim_empty_columns off to keep it",
+                    i + 1
+                )));
+            }
+        }
+        if batch.num_columns() == self.width {
+            return Ok(Some(batch));
+        }
+        batch
+            .project(&(0..self.width).collect::<Vec<_>>())
+            .map(Some)
+    }
+}
+
+pub async fn cp_csv_to_arrow(
+    arr_buffer: ArrayBuffer,
+    name: String,
+    csv_config: JsValue,
+) -> Result<CsvImport, ArrowError> {
+    cp_csv_source_to_arrow(
+        CsvSource::Buffer(Uint8Array::new(&arr_buffer)),
+        name,
+        csv_config,
+    )
+    .await
+}
+
+/// Converts the CSV in `source` to `<name>.arrow` and returns the schema of the table.
+/// The source is decoded chunk by chunk and every record batch goes to the writable
+/// stream of the file right away, so only one chunk and one batch are held in memory.
+/// With `on_error` `skip` or `null_fill` the rows that cannot be read are written to
+/// `<name>.rejected.arrow`, also if the import fails for exceeding `max_errors`.
+pub async fn cp_csv_source_to_arrow(
+    source: CsvSource,
+    name: String,
+    csv_config: JsValue,
+) -> Result<CsvImport, ArrowError> {
+    let location = Path::from(format!("{name}.arrow"));
+    let mut read = CsvRead::open(source, csv_config, location.clone()).await?;
+    let schema = Arc::clone(&read.schema);
+    let formats = ColumnFormats::new(&schema, &read.cfg);
+    let mut errors = ErrorHandling::new(&read.cfg, schema.fields().len() + read.padding);
+    // a tolerant import converts the values itself, so it decodes them as strings
+    let decode_schema = match errors {
+        Some(_) => string_schema(&schema),
+        None => formats.decode_schema(&schema),
+    };
+    let mut decoder = read.decoder(&decode_schema, None);
+    let convert = |batch: RecordBatch, errors: &mut Option<ErrorHandling>| match errors {
+        Some(errors) => errors.batch(batch, &schema, &formats),
+        None => formats.apply(batch, &schema),
+    };
+
+    let writer = FileWriterState::open(&location).await.map_err(io_error)?;
+    let mut rows = 0;
+    let result = async {
+        let options = IpcWriteOptions::try_new(8, false, MetadataVersion::V5)?;
+        let mut ipc = FileWriter::try_new_with_options(Vec::new(), &schema, options)?;
+        let mut chunk = std::mem::take(&mut read.first);
+        let mut offset = read.first_end;
+        loop {
+            if let Some(errors) = &mut errors {
+                chunk = errors.records(&chunk, offset >= read.len())?;
+            }
+            let mut buf = &chunk[..];
+            while !buf.is_empty() {
+                let decoded = decoder.decode(buf)?;
+                buf = &buf[decoded..];
+                if decoder.capacity() == 0 {
+                    if let Some(batch) = decoder.flush()? {
+                        let batch = convert(batch, &mut errors)?;
+                        rows += batch.num_rows();
+                        ipc.write(&batch)?;
+                        drain_ipc(&writer, &location, &mut ipc).await?;
+                    }
+                }
+            }
+            if offset >= read.len() {
+                break;
+            }
+            (chunk, offset) = read.chunk(offset).await?;
+        }
+        // an empty input tells the decoder the last row is complete
+        decoder.decode(&[])?;
+        if let Some(batch) = decoder.flush()? {
+            let batch = convert(batch, &mut errors)?;
+            rows += batch.num_rows();
+            ipc.write(&batch)?;
+        }
+        ipc.finish()?;
+        drain_ipc(&writer, &location, &mut ipc).await?;
+        if let Some(errors) = &errors {
+            write_rejected(errors, &name).await?;
+        }
+        writer.complete(&location).await.map_err(io_error)?;
+        Ok(())
+    }
+    .await;
+
+    if let Err(e) = result {
+        let _ = writer.discard(&location).await;
+        if let Some(errors) = errors.as_ref().filter(|errors| errors.exceeded()) {
+            let _ = write_rejected(errors, &name).await;
+        }
+        return Err(e);
+    }
+    Ok(CsvImport {
+        schema: TableSchema::new(name.clone(), &schema),
+        rows,
+        errors: errors.as_ref().map_or(0, ErrorHandling::errors),
+        rejected_table: errors.map(|_| format!("{name}.rejected")),
+    })
+}
+
+/// Writes the rows rejected by an import to `<name>.rejected.arrow`
+async fn write_rejected(errors: &ErrorHandling, name: &str) -> Result<(), ArrowError> {
+    let batch = errors.rejected_batch()?;
+    let location = Path::from(format!("{name}.rejected.arrow"));
+    write_arrow_file(&location, &batch.schema(), stream::iter([Ok(batch)])).await
+}
+
+/// `schema` with every column as string
+fn string_schema(schema: &Schema) -> Schema {
+    Schema::new(
+        schema
+            .fields()
+            .iter()
+            .map(|field| field.as_ref().clone().with_data_type(DataType::Utf8))
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Reads the first `n_rows` rows of the CSV in `source` with the schema an import with
+/// the same config would use, without writing anything. Values that do not parse are
+/// reported instead of failing the preview.
+pub(crate) async fn preview_csv_source(
+    source: CsvSource,
+    csv_config: JsValue,
+    n_rows: usize,
+) -> Result<CsvPreview, ArrowError> {
+    let mut read = CsvRead::open(source, csv_config, Path::from("preview.csv")).await?;
+    let schema = Arc::clone(&read.schema);
+    // every column is read as string, so a value of the wrong type is reported as error
+    let strings = Arc::new(string_schema(&schema));
+    let mut decoder = read.decoder(&strings, Some(n_rows));
+
+    let mut errors = Vec::new();
+    let decoded = async {
+        let mut chunk = std::mem::take(&mut read.first);
+        let mut offset = read.first_end;
+        loop {
+            let mut buf = &chunk[..];
+            while !buf.is_empty() && decoder.capacity() > 0 {
+                let decoded = decoder.decode(buf)?;
+                buf = &buf[decoded..];
+            }
+            if decoder.capacity() == 0 || offset >= read.len() {
+                break;
+            }
+            (chunk, offset) = read.chunk(offset).await?;
+        }
+        if decoder.capacity() > 0 {
+            decoder.decode(&[])?;
+        }
+        Ok::<(), ArrowError>(())
+    }
+    .await;
+    if let Err(e) = decoded {
+        // a row that cannot be split into fields ends the preview
+        errors.push(CellError::row(&e));
+    }
+    let batch = match decoder.flush() {
+        Ok(batch) => batch.unwrap_or_else(|| RecordBatch::new_empty(Arc::clone(&strings))),
+        Err(e) => {
+            errors.push(CellError::row(&e));
+            RecordBatch::new_empty(Arc::clone(&strings))
+        }
+    };
+
+    let null_counts = schema
+        .fields()
+        .iter()
+        .zip(batch.columns())
+        .map(|(field, column)| (field.name().clone(), column.null_count()))
+        .collect();
+    let formats = ColumnFormats::new(&schema, &read.cfg);
+    let (batch, cell_errors) = formats.convert(&batch, &schema, 0)?;
+    errors.splice(0..0, cell_errors);
+
+    let mut writer = StreamWriter::try_new(Vec::new(), &schema)?;
+    writer.write(&batch)?;
+    writer.finish()?;
+    Ok(CsvPreview {
+        schema: TableSchema::new(String::new(), &schema),
+        rows: writer.into_inner()?,
+        null_counts,
+        errors,
+    })
+}
+
+/// Splits `chunk` after its last newline, unless it is the end of the file
+fn split_complete_rows(chunk: &[u8], last: bool) -> (&[u8], Vec<u8>) {
+    match chunk.iter().rposition(|b| *b == b'\n') {
+        Some(end) if !last => (&chunk[..=end], chunk[end + 1..].to_vec()),
+        _ => (chunk, Vec::new()),
+    }
+}
+
+/// Writes `batches` to `location` as an Arrow IPC file, moving the bytes of every batch
+/// to the file before the next one is read
+pub(crate) async fn write_arrow_file(
+    location: &Path,
+    schema: &Schema,
+    batches: impl Stream<Item = Result<RecordBatch, ArrowError>>,
+) -> Result<(), ArrowError> {
+    let writer = FileWriterState::open(location).await.map_err(io_error)?;
+    let result = async {
+        let options = IpcWriteOptions::try_new(8, false, MetadataVersion::V5)?;
+        let mut ipc = FileWriter::try_new_with_options(Vec::new(), schema, options)?;
+        let mut batches = std::pin::pin!(batches);
+        while let Some(batch) = batches.next().await {
+            ipc.write(&batch?)?;
+            drain_ipc(&writer, location, &mut ipc).await?;
+        }
+        ipc.finish()?;
+        drain_ipc(&writer, location, &mut ipc).await?;
+        writer.complete(location).await.map_err(io_error)?;
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        let _ = writer.discard(location).await;
+    }
+    result
+}
+
+/// Moves the IPC bytes written so far from `ipc` to the file
+async fn drain_ipc(
+    writer: &FileWriterState,
+    location: &Path,
+    ipc: &mut FileWriter<Vec<u8>>,
+) -> Result<(), ArrowError> {
+    let bytes = std::mem::take(ipc.get_mut());
+    writer
+        .write(location, PutPayload::from(bytes))
+        .await
+        .map_err(io_error)
+}
+
+fn io_error(error: OpfsError) -> ArrowError {
+    ArrowError::ExternalError(Box::new(object_store::Error::from(error)))
+}
@@ -0,0 +1,349 @@
+use std::collections::{HashMap, HashSet};
+
+use datafusion::arrow::error::ArrowError;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::csv_import::CsvConfig;
+
+/// Bytes at the start of a file the dialect is guessed from
+pub(crate) const SNIFF_BYTES: usize = 64 * 1024;
+/// Rows of the sample looked at
+const SNIFF_ROWS: usize = 100;
+const DELIMITERS: [char; 4] = [',', ';', '\t', '|'];
+const QUOTES: [char; 2] = ['"', '\''];
+
+static DECIMAL_COMMA: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^[+-]?(\d{1,3}(\.\d{3})+|\d+),\d+$").unwrap());
+static DECIMAL_POINT: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^[+-]?(\d{1,3}(,\d{3})+|\d+)\.\d+$").unwrap());
+/// Numbers, dates and times, none of which are likely column names
+static VALUE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[+-]?[\d.,]*\d[\d.,:/ T-]*$").unwrap());
+
+/// Dialect of a CSV file as guessed by [`sniff`]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CsvDialect {
+    pub encoding: Encoding,
+    pub delimiter: char,
+    pub quote: char,
+    pub has_header: bool,
+    /// `.` or `,`
+    pub decimal: char,
+    /// Fields per row, including the empty ones at the end
+    pub columns: usize,
+    /// Columns at the end that are empty in every sampled row, header included
+    pub trailing_empty_columns: usize,
+}
+
+/// Character encodings an import converts to UTF-8
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+pub(crate) enum Encoding {
+    #[serde(rename = "utf-8")]
+    Utf8,
+    #[serde(rename = "utf-16le")]
+    Utf16Le,
+    #[serde(rename = "utf-16be")]
+    Utf16Be,
+    #[serde(rename = "windows-1252")]
+    Windows1252,
+}
+
+/// Characters of the bytes 0x80 to 0x9f in windows-1252, the other bytes map to the
+/// code point of the same value
+const WINDOWS_1252: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+impl Encoding {
+    /// The names of the WHATWG encoding standard, plus `latin1` for windows-1252
+    pub fn from_label(label: &str) -> Result<Encoding, ArrowError> {
+        match label.to_ascii_lowercase().as_str() {
+            "" | "utf-8" | "utf8" => Ok(Encoding::Utf8),
+            "utf-16le" | "utf-16" => Ok(Encoding::Utf16Le),
+            "utf-16be" => Ok(Encoding::Utf16Be),
+            "windows-1252" | "cp1252" | "latin1" | "iso-8859-1" => Ok(Encoding::Windows1252),
+            _ => Err(ArrowError::InvalidArgumentError(format!(
+                "Unsupported encoding {label}"
+            ))),
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Windows1252 => "windows-1252",
+        }
+    }
+
+    /// Guesses the encoding from the byte order mark, or the bytes themselves if there is none
+    fn detect(bytes: &[u8]) -> Encoding {
+        if bytes.starts_with(&[0xef, 0xbb, 0xbf]) {
+            return Encoding::Utf8;
+        } else if bytes.starts_with(&[0xff, 0xfe]) {
+            return Encoding::Utf16Le;
+        } else if bytes.starts_with(&[0xfe, 0xff]) {
+            return Encoding::Utf16Be;
+        }
+        // ASCII text in UTF-16 has every other byte zero
+        let zeros = |parity: usize| {
+            bytes
+                .iter()
+                .skip(parity)
+                .step_by(2)
+                .filter(|b| **b == 0)
+                .count()
+        };
+        let half = bytes.len() / 4;
+        if bytes.len() >= 4 && zeros(1) > half {
+            return Encoding::Utf16Le;
+        } else if bytes.len() >= 4 && zeros(0) > half {
+            return Encoding::Utf16Be;
+        }
+        match std::str::from_utf8(bytes) {
+            Ok(_) => Encoding::Utf8,
+            // the sample may end in the middle of a character
+            Err(e) if e.error_len().is_none() => Encoding::Utf8,
+            Err(_) => Encoding::Windows1252,
+        }
+    }
+
+    /// Converts `bytes` to UTF-8, `start` drops the byte order mark at the start of the file.
+    /// Returns the number of bytes converted: unless `last`, a UTF-16 chunk leaves an odd
+    /// byte or a high surrogate at its end to the next one. UTF-16 input must start at an
+    /// even offset.
+    pub fn decode(&self, mut bytes: Vec<u8>, start: bool, last: bool) -> (Vec<u8>, usize) {
+        let len = bytes.len();
+        match self {
+            Encoding::Utf8 => {
+                if start && bytes.starts_with(&[0xef, 0xbb, 0xbf]) {
+                    bytes.drain(..3);
+                }
+                (bytes, len)
+            }
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                let bom = if start
+                    && (bytes.starts_with(&[0xff, 0xfe]) || bytes.starts_with(&[0xfe, 0xff]))
+                {
+                    2
+                } else {
+                    0
+                };
+                let mut units: Vec<u16> = bytes[bom..]
+                    .chunks_exact(2)
+                    .map(|pair| match self {
+                        Encoding::Utf16Le => u16::from_le_bytes([pair[0], pair[1]]),
+                        _ => u16::from_be_bytes([pair[0], pair[1]]),
+                    })
+                    .collect();
+                if !last
+                    && units
+                        .last()
+                        .is_some_and(|unit| (0xd800..0xdc00).contains(unit))
+                {
+                    units.pop();
+                }
+                let decoded = char::decode_utf16(units.iter().copied())
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect::<String>()
+                    .into_bytes();
+                let consumed = if last { len } else { bom + 2 * units.len() };
+                (decoded, consumed)
+            }
+            Encoding::Windows1252 => (
+                bytes
+                    .iter()
+                    .map(|b| match b {
+                        0x80..=0x9f => WINDOWS_1252[(b - 0x80) as usize],
+                        _ => char::from(*b),
+                    })
+                    .collect::<String>()
+                    .into_bytes(),
+                len,
+            ),
+        }
+    }
+}
+
+/// Guesses the dialect from the start of a file, `complete` if `bytes` holds all of it
+pub(crate) fn sniff(bytes: &[u8], complete: bool) -> CsvDialect {
+    let sample = &bytes[..bytes.len().min(SNIFF_BYTES)];
+    let encoding = Encoding::detect(sample);
+    let (text, _) = encoding.decode(sample.to_vec(), true, sample.len() == bytes.len());
+    let text = String::from_utf8_lossy(&text);
+    let mut lines: Vec<&str> = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    if !(complete && sample.len() == bytes.len()) && lines.len() > 1 {
+        // the sample ends in the middle of a row
+        lines.pop();
+    }
+    lines.truncate(SNIFF_ROWS);
+
+    let quote = sniff_quote(&lines);
+    let delimiter = sniff_delimiter(&lines, quote);
+    let rows: Vec<Vec<String>> = lines
+        .iter()
+        .map(|line| split_fields(line, delimiter, quote))
+        .collect();
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    // rows without any value say nothing about the columns
+    let trailing_empty_columns = rows
+        .iter()
+        .filter_map(|row| row.iter().rposition(|field| !field.is_empty()))
+        .map(|last| columns - last - 1)
+        .min()
+        .unwrap_or(0);
+    let width = columns - trailing_empty_columns;
+    let decimal = if delimiter == ',' {
+        '.'
+    } else {
+        sniff_decimal(&rows)
+    };
+
+    CsvDialect {
+        encoding,
+        delimiter,
+        quote,
+        has_header: sniff_header(&rows, width),
+        decimal,
+        columns,
+        trailing_empty_columns,
+    }
+}
+
+/// The quote character found most often next to a delimiter or the end of a line
+fn sniff_quote(lines: &[&str]) -> char {
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for line in lines {
+        let chars: Vec<char> = line.chars().collect();
+        for (i, c) in chars.iter().enumerate() {
+            if !QUOTES.contains(c) {
+                continue;
+            }
+            let opens = i == 0 || DELIMITERS.contains(&chars[i - 1]);
+            let closes = i + 1 == chars.len() || DELIMITERS.contains(&chars[i + 1]);
+            if opens || closes {
+                *counts.entry(*c).or_default() += 1;
+            }
+        }
+    }
+    QUOTES
+        .iter()
+        .copied()
+        .max_by_key(|quote| (counts.get(quote).copied().unwrap_or(0), *quote == '"'))
+        .unwrap_or('"')
+}
+
+/// The delimiter found the same number of times in the most lines
+fn sniff_delimiter(lines: &[&str], quote: char) -> char {
+    let mut best = (',', 0, 0);
+    for delimiter in DELIMITERS {
+        let mut frequencies: HashMap<usize, usize> = HashMap::new();
+        for line in lines {
+            let count = split_fields(line, delimiter, quote).len() - 1;
+            *frequencies.entry(count).or_default() += 1;
+        }
+        let consistent = frequencies
+            .into_iter()
+            .filter(|(count, _)| *count > 0)
+            .max_by_key(|(count, lines)| (*lines, *count));
+        if let Some((count, lines)) = consistent {
+            if (lines, count) > (best.1, best.2) {
+                best = (delimiter, lines, count);
+            }
+        }
+    }
+    best.0
+}
+
+/// `,` if more values look like `1.234,5` than like `1,234.5`
+fn sniff_decimal(rows: &[Vec<String>]) -> char {
+    let fields = rows.iter().flatten();
+    let commas = fields
+        .clone()
+        .filter(|field| DECIMAL_COMMA.is_match(field))
+        .count();
+    let points = fields.filter(|field| DECIMAL_POINT.is_match(field)).count();
+    if commas > points {
+        ','
+    } else {
+        '.'
+    }
+}
+
+/// Whether the first row names the columns: its fields are distinct and none of them
+/// looks like a value, or it has a name above a column holding nothing but values
+fn sniff_header(rows: &[Vec<String>], width: usize) -> bool {
+    let header = match rows.first() {
+        Some(header) => &header[..width.min(header.len())],
+        None => return true,
+    };
+    let distinct = header.iter().collect::<HashSet<_>>().len() == header.len();
+    if !distinct || header.iter().any(|field| field.is_empty()) {
+        return false;
+    }
+    if !header.iter().any(|field| VALUE.is_match(field)) {
+        return true;
+    }
+    header.iter().enumerate().any(|(i, name)| {
+        !VALUE.is_match(name)
+            && rows.len() > 1
+            && rows[1..]
+                .iter()
+                .filter_map(|row| row.get(i).filter(|field| !field.is_empty()))
+                .all(|field| VALUE.is_match(field))
+    })
+}
+
+/// Fields of `line`, trimmed and without their quotes
+fn split_fields(line: &str, delimiter: char, quote: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    for c in line.chars() {
+        if c == quote {
+            quoted = !quoted;
+        } else if c == delimiter && !quoted {
+            fields.push(field.trim().to_string());
+            field.clear();
+        } else {
+            field.push(c);
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+impl CsvConfig {
+    /// Fills the settings left empty with the guesses of `dialect`
+    pub(crate) fn or_dialect(mut self, dialect: &CsvDialect) -> CsvConfig {
+        if self.encoding.is_empty() {
+            self.encoding = dialect.encoding.label().to_string();
+        }
+        if self.delimiter.is_empty() {
+            self.delimiter = dialect.delimiter.to_string();
+        }
+        if self.quote.is_empty() {
+            self.quote = dialect.quote.to_string();
+        }
+        if self.decimal.is_empty() {
+            // a decimal comma only makes sense if the comma is not the delimiter
+            let decimal = if self.delimiter == "," {
+                '.'
+            } else {
+                dialect.decimal
+            };
+            self.decimal = decimal.to_string();
+        }
+        self.has_header.get_or_insert(dialect.has_header);
+        self
+    }
+}
@@ -0,0 +1,247 @@
+use std::error::Error;
+
+use datafusion::arrow::error::ArrowError;
+use datafusion::error::DataFusionError;
+use datafusion::parquet::errors::ParquetError;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use wasm_bindgen::prelude::*;
+
+/// What went wrong, coarse enough for the UI to decide whether the user has to fix
+/// something or the engine failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The SQL text or an options object could not be parsed
+    Parse,
+    /// The statement parsed, but could not be planned, e.g. an unknown table or function
+    Plan,
+    /// Running the plan failed
+    Execution,
+    /// Reading from or writing to OPFS failed
+    Io,
+    /// Data does not match the expected schema or types
+    Schema,
+    /// A memory or storage limit was hit
+    Resource,
+    /// The query was aborted through its signal or ran into its timeout
+    Cancelled,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Parse => "parse",
+            ErrorKind::Plan => "plan",
+            ErrorKind::Execution => "execution",
+            ErrorKind::Io => "io",
+            ErrorKind::Schema => "schema",
+            ErrorKind::Resource => "resource",
+            ErrorKind::Cancelled => "cancelled",
+        }
+    }
+
+    fn of_arrow(error: &ArrowError) -> ErrorKind {
+        match error {
+            ArrowError::IoError(..) | ArrowError::ParquetError(_) => ErrorKind::Io,
+            ArrowError::ExternalError(e) => {
+                if let Some(e) = e.downcast_ref::<DataFusionError>() {
+                    ErrorKind::of(e)
+                } else if e.is::<object_store::Error>() {
+                    ErrorKind::Io
+                } else {
+                    ErrorKind::Execution
+                }
+            }
+            ArrowError::ParseError(_)
+            | ArrowError::CsvError(_)
+            | ArrowError::JsonError(_)
+            | ArrowError::SchemaError(_)
+            | ArrowError::CastError(_)
+            | ArrowError::InvalidArgumentError(_) => ErrorKind::Schema,
+            ArrowError::MemoryError(_) => ErrorKind::Resource,
+            _ => ErrorKind::Execution,
+        }
+    }
+
+    fn of(error: &DataFusionError) -> ErrorKind {
+        match error.find_root() {
+            DataFusionError::SQL(..) => ErrorKind::Parse,
+            DataFusionError::Plan(_)
+            | DataFusionError::NotImplemented(_)
+            | DataFusionError::Configuration(_) => ErrorKind::Plan,
+            DataFusionError::SchemaError(..) => ErrorKind::Schema,
+            DataFusionError::ArrowError(e, _) => ErrorKind::of_arrow(e),
+            DataFusionError::ObjectStore(_)
+            | DataFusionError::IoError(_)
+            | DataFusionError::ParquetError(_) => ErrorKind::Io,
+            DataFusionError::ResourcesExhausted(_) => ErrorKind::Resource,
+            DataFusionError::External(e) => {
+                if let Some(e) = e.downcast_ref::<ArrowError>() {
+                    ErrorKind::of_arrow(e)
+                } else if e.is::<object_store::Error>() {
+                    ErrorKind::Io
+                } else {
+                    ErrorKind::Execution
+                }
+            }
+            _ => ErrorKind::Execution,
+        }
+    }
+}
+
+/// Position of the offending part of a SQL statement, lines and columns start at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SqlSpan {
+    line: u32,
+    column: u32,
+    end: Option<(u32, u32)>,
+}
+
+static PARSER_POSITION: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"at Line: (\d+), Column: (\d+)").unwrap());
+
+/// The sql parser only reports where it gave up as part of its message
+fn parser_span(message: &str) -> Option<SqlSpan> {
+    let captures = PARSER_POSITION.captures(message)?;
+    Some(SqlSpan {
+        line: captures[1].parse().ok()?,
+        column: captures[2].parse().ok()?,
+        end: None,
+    })
+}
+
+/// Error thrown to JS by every export
+#[wasm_bindgen]
+#[derive(Debug, Clone)]
+pub struct QueryError {
+    kind: ErrorKind,
+    message: String,
+    span: Option<SqlSpan>,
+    chain: Vec<String>,
+}
+
+impl QueryError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> QueryError {
+        QueryError {
+            kind,
+            message: message.into(),
+            span: None,
+            chain: Vec::new(),
+        }
+    }
+
+    pub fn error_kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+#[wasm_bindgen]
+impl QueryError {
+    /// One of `parse`, `plan`, `execution`, `io`, `schema`, `resource` or `cancelled`
+    #[wasm_bindgen(getter)]
+    pub fn kind(&self) -> String {
+        self.kind.as_str().to_string()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+
+    /// Line of the SQL statement the error points at
+    #[wasm_bindgen(getter)]
+    pub fn line(&self) -> Option<u32> {
+        self.span.map(|span| span.line)
+    }
+
+    /// Column of the SQL statement the error points at
+    #[wasm_bindgen(getter)]
+    pub fn column(&self) -> Option<u32> {
+        self.span.map(|span| span.column)
+    }
+
+    #[wasm_bindgen(getter, js_name = endLine)]
+    pub fn end_line(&self) -> Option<u32> {
+        self.span.and_then(|span| span.end).map(|(line, _)| line)
+    }
+
+    #[wasm_bindgen(getter, js_name = endColumn)]
+    pub fn end_column(&self) -> Option<u32> {
+        self.span
+            .and_then(|span| span.end)
+            .map(|(_, column)| column)
+    }
+
+    /// Every error in the chain, outermost first
+    #[wasm_bindgen(getter)]
+    pub fn chain(&self) -> Vec<String> {
+        self.chain.clone()
+    }
+
+    #[wasm_bindgen(js_name = toString)]
+    pub fn to_js_string(&self) -> String {
+        format!("{} error: {}", self.kind.as_str(), self.message)
+    }
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<DataFusionError> for QueryError {
+    fn from(error: DataFusionError) -> Self {
+        let diagnostic = error.diagnostic();
+        let message = match diagnostic {
+            Some(diagnostic) => diagnostic.message.clone(),
+            None => error.find_root().message().to_string(),
+        };
+        let span = diagnostic
+            .and_then(|diagnostic| diagnostic.span)
+            .map(|span| SqlSpan {
+                line: span.start.line as u32,
+                column: span.start.column as u32,
+                end: Some((span.end.line as u32, span.end.column as u32)),
+            })
+            .or_else(|| parser_span(&message));
+
+        let mut chain = vec![error.to_string()];
+        let mut source = error.source();
+        while let Some(e) = source {
+            chain.push(e.to_string());
+            source = e.source();
+        }
+
+        QueryError {
+            kind: ErrorKind::of(&error),
+            message,
+            span,
+            chain,
+        }
+    }
+}
+
+impl From<ArrowError> for QueryError {
+    fn from(error: ArrowError) -> Self {
+        DataFusionError::from(error).into()
+    }
+}
+
+impl From<ParquetError> for QueryError {
+    fn from(error: ParquetError) -> Self {
+        DataFusionError::from(error).into()
+    }
+}
+
+impl From<object_store::Error> for QueryError {
+    fn from(error: object_store::Error) -> Self {
+        DataFusionError::from(error).into()
+    }
+}
+
+impl From<serde_wasm_bindgen::Error> for QueryError {
+    fn from(error: serde_wasm_bindgen::Error) -> Self {
+        QueryError::new(ErrorKind::Parse, format!("Invalid options: {error}"))
+    }
+}
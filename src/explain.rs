@@ -0,0 +1,296 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::common::Result;
+use datafusion::execution::{SendableRecordBatchStream, TaskContext};
+use datafusion::logical_expr::LogicalPlan;
+use datafusion::physical_plan::aggregates::AggregateExec;
+use datafusion::physical_plan::filter::FilterExec;
+use datafusion::physical_plan::joins::utils::JoinFilter;
+use datafusion::physical_plan::joins::{HashJoinExec, NestedLoopJoinExec, SortMergeJoinExec};
+use datafusion::physical_plan::projection::ProjectionExec;
+use datafusion::physical_plan::repartition::RepartitionExec;
+use datafusion::physical_plan::sorts::sort::SortExec;
+use datafusion::physical_plan::sorts::sort_preserving_merge::SortPreservingMergeExec;
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::windows::{BoundedWindowAggExec, WindowAggExec};
+use datafusion::physical_plan::{
+    displayable, DisplayAs, DisplayFormatType, ExecutionPlan, ExecutionPlanProperties,
+    Partitioning, PlanProperties,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+/// Options of `explain_sql`, `{ analyze?: boolean, verbose?: boolean }`
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct ExplainOptions {
+    /// Run the query and add the actual row counts and timings
+    pub analyze: bool,
+    /// Add the unoptimized logical plan, the schema of every logical node and all metrics
+    pub verbose: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Explanation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_logical: Option<LogicalNode>,
+    pub logical: LogicalNode,
+    pub physical: PhysicalNode,
+    /// Size of the result in memory, only known with `analyze`
+    pub output_bytes: Option<usize>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LogicalNode {
+    operator: String,
+    description: String,
+    expressions: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schema: Option<Vec<String>>,
+    children: Vec<LogicalNode>,
+}
+
+impl LogicalNode {
+    pub fn new(plan: &LogicalPlan, verbose: bool) -> LogicalNode {
+        let description = plan.display().to_string();
+        let schema = verbose.then(|| {
+            plan.schema()
+                .fields()
+                .iter()
+                .map(|field| format!("{}: {}", field.name(), field.data_type()))
+                .collect()
+        });
+        LogicalNode {
+            operator: operator(&description),
+            expressions: plan.expressions().iter().map(|e| e.to_string()).collect(),
+            schema,
+            children: plan
+                .inputs()
+                .into_iter()
+                .map(|input| LogicalNode::new(input, verbose))
+                .collect(),
+            description,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PhysicalNode {
+    operator: String,
+    /// Expressions and settings of the operator, as printed by `EXPLAIN`
+    description: String,
+    /// Projected, filtered, grouped, aggregated, sorted or joined on by the operator
+    expressions: Vec<String>,
+    partitioning: String,
+    estimated_rows: Option<usize>,
+    estimated_bytes: Option<usize>,
+    actual_rows: Option<usize>,
+    elapsed_compute_ns: Option<usize>,
+    /// Size in memory of the batches the node produced, only known with `analyze`
+    output_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metrics: Option<BTreeMap<String, usize>>,
+    children: Vec<PhysicalNode>,
+}
+
+impl PhysicalNode {
+    pub fn new(plan: &Arc<dyn ExecutionPlan>, verbose: bool) -> PhysicalNode {
+        if let Some(measured) = plan.as_any().downcast_ref::<MeasuredExec>() {
+            let mut node = PhysicalNode::new(&measured.input, verbose);
+            node.output_bytes = Some(measured.output_bytes.load(Ordering::Relaxed));
+            return node;
+        }
+        let statistics = plan.statistics().ok();
+        let metrics = plan.metrics().map(|metrics| metrics.aggregate_by_name());
+        PhysicalNode {
+            operator: plan.name().to_string(),
+            description: displayable(plan.as_ref())
+                .one_line()
+                .to_string()
+                .trim_end()
+                .to_string(),
+            expressions: expressions(plan.as_ref()),
+            partitioning: plan.output_partitioning().to_string(),
+            estimated_rows: statistics
+                .as_ref()
+                .and_then(|s| s.num_rows.get_value().copied()),
+            estimated_bytes: statistics
+                .as_ref()
+                .and_then(|s| s.total_byte_size.get_value().copied()),
+            actual_rows: metrics.as_ref().and_then(|m| m.output_rows()),
+            elapsed_compute_ns: metrics.as_ref().and_then(|m| m.elapsed_compute()),
+            output_bytes: None,
+            metrics: metrics.filter(|_| verbose).map(|metrics| {
+                metrics
+                    .iter()
+                    .map(|metric| (metric.value().name().to_string(), metric.value().as_usize()))
+                    .collect()
+            }),
+            children: plan
+                .children()
+                .into_iter()
+                .map(|child| PhysicalNode::new(child, verbose))
+                .collect(),
+        }
+    }
+}
+
+/// `Projection: t.a` -> `Projection`
+fn operator(description: &str) -> String {
+    description
+        .split(|c: char| c == ':' || c.is_whitespace())
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// The expressions of the operators that have any, empty for the others
+fn expressions(plan: &dyn ExecutionPlan) -> Vec<String> {
+    let any = plan.as_any();
+    let join = |on: &[(_, _)], filter: Option<&_>| {
+        on.iter()
+            .map(|(left, right)| format!("{left} = {right}"))
+            .chain(filter.map(|filter: &JoinFilter| filter.expression().to_string()))
+            .collect()
+    };
+    if let Some(projection) = any.downcast_ref::<ProjectionExec>() {
+        projection
+            .expr()
+            .iter()
+            .map(|(expr, name)| format!("{expr} as {name}"))
+            .collect()
+    } else if let Some(filter) = any.downcast_ref::<FilterExec>() {
+        vec![filter.predicate().to_string()]
+    } else if let Some(aggregate) = any.downcast_ref::<AggregateExec>() {
+        let groups = aggregate.group_expr().expr().iter();
+        groups
+            .map(|(expr, name)| format!("{expr} as {name}"))
+            .chain(
+                aggregate
+                    .aggr_expr()
+                    .iter()
+                    .map(|expr| expr.name().to_string()),
+            )
+            .collect()
+    } else if let Some(sort) = any.downcast_ref::<SortExec>() {
+        sort.expr().iter().map(|expr| expr.to_string()).collect()
+    } else if let Some(merge) = any.downcast_ref::<SortPreservingMergeExec>() {
+        merge.expr().iter().map(|expr| expr.to_string()).collect()
+    } else if let Some(hash_join) = any.downcast_ref::<HashJoinExec>() {
+        join(hash_join.on(), hash_join.filter())
+    } else if let Some(merge_join) = any.downcast_ref::<SortMergeJoinExec>() {
+        join(merge_join.on(), merge_join.filter().as_ref())
+    } else if let Some(loop_join) = any.downcast_ref::<NestedLoopJoinExec>() {
+        join(&[], loop_join.filter())
+    } else if let Some(repartition) = any.downcast_ref::<RepartitionExec>() {
+        match repartition.partitioning() {
+            Partitioning::Hash(exprs, _) => exprs.iter().map(|expr| expr.to_string()).collect(),
+            _ => Vec::new(),
+        }
+    } else if let Some(window) = any.downcast_ref::<WindowAggExec>() {
+        window
+            .window_expr()
+            .iter()
+            .map(|expr| expr.name().to_string())
+            .collect()
+    } else if let Some(window) = any.downcast_ref::<BoundedWindowAggExec>() {
+        window
+            .window_expr()
+            .iter()
+            .map(|expr| expr.name().to_string())
+            .collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Passes the batches of its input through and adds up their size in memory, so
+/// `explain_sql` with `analyze` can tell the output bytes of every node
+#[derive(Debug)]
+pub(crate) struct MeasuredExec {
+    input: Arc<dyn ExecutionPlan>,
+    output_bytes: Arc<AtomicUsize>,
+}
+
+impl MeasuredExec {
+    /// `plan` with every node wrapped
+    pub fn wrap(plan: Arc<dyn ExecutionPlan>) -> Result<Arc<dyn ExecutionPlan>> {
+        let children = plan
+            .children()
+            .into_iter()
+            .map(|child| MeasuredExec::wrap(Arc::clone(child)))
+            .collect::<Result<Vec<_>>>()?;
+        let input = if children.is_empty() {
+            plan
+        } else {
+            plan.with_new_children(children)?
+        };
+        Ok(Arc::new(MeasuredExec {
+            input,
+            output_bytes: Arc::default(),
+        }))
+    }
+}
+
+impl DisplayAs for MeasuredExec {
+    fn fmt_as(&self, _: DisplayFormatType, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MeasuredExec")
+    }
+}
+
+impl ExecutionPlan for MeasuredExec {
+    fn name(&self) -> &str {
+        "MeasuredExec"
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn properties(&self) -> &PlanProperties {
+        self.input.properties()
+    }
+
+    fn maintains_input_order(&self) -> Vec<bool> {
+        vec![true]
+    }
+
+    fn children(&self) -> Vec<&Arc<dyn ExecutionPlan>> {
+        vec![&self.input]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        mut children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Ok(Arc::new(MeasuredExec {
+            input: children.swap_remove(0),
+            output_bytes: Arc::clone(&self.output_bytes),
+        }))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let output_bytes = Arc::clone(&self.output_bytes);
+        let stream = self.input.execute(partition, context)?;
+        let schema = stream.schema();
+        let measured = stream.inspect(move |batch| {
+            if let Ok(batch) = batch {
+                output_bytes
+                    .fetch_add(RecordBatch::get_array_memory_size(batch), Ordering::Relaxed);
+            }
+        });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(schema, measured)))
+    }
+}
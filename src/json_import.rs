@@ -0,0 +1,153 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use datafusion::arrow::error::ArrowError;
+use datafusion::arrow::json::reader::{
+    infer_json_schema_from_iterator, infer_json_schema_from_seekable,
+};
+use datafusion::arrow::json::ReaderBuilder;
+use futures::stream;
+use js_sys::{ArrayBuffer, Uint8Array};
+use object_store::path::Path;
+
+use crate::csv_import::write_arrow_file;
+
+/// Rows decoded per batch when ingesting a top-level JSON array
+const JSON_BATCH_SIZE: usize = 1024;
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Converts NDJSON, or a JSON document holding an array of objects, to `<name>.arrow`.
+/// The schema is inferred from all records, nested objects become structs and arrays lists.
+pub async fn cp_json_to_arrow(arr_buffer: ArrayBuffer, name: String) -> Result<(), ArrowError> {
+    let mut bytes = Uint8Array::new(&arr_buffer).to_vec();
+    if bytes.starts_with(UTF8_BOM) {
+        bytes.drain(..UTF8_BOM.len());
+    }
+    let first = bytes.iter().find(|b| !b.is_ascii_whitespace());
+    let location = Path::from(format!("{name}.arrow"));
+
+    if first == Some(&b'[') {
+        // elements are parsed one at a time, once to infer the schema and once to decode
+        let values = ArrayElements::new(&bytes).map(|element| {
+            serde_json::from_slice::<serde_json::Value>(element?)
+                .map_err(|e| ArrowError::JsonError(format!("Invalid JSON array: {e}")))
+        });
+        let schema = infer_json_schema_from_iterator(values)?;
+        let mut decoder = ReaderBuilder::new(Arc::new(schema.clone()))
+            .with_batch_size(JSON_BATCH_SIZE)
+            .build_decoder()?;
+        let mut elements = ArrayElements::new(&bytes);
+        let batches = std::iter::from_fn(move || {
+            for element in elements.by_ref().take(JSON_BATCH_SIZE) {
+                if let Err(e) = element.and_then(|element| decoder.decode(element)) {
+                    return Some(Err(e));
+                }
+            }
+            decoder.flush().transpose()
+        });
+        write_arrow_file(&location, &schema, stream::iter(batches)).await
+    } else {
+        let mut bytes_cursor = Cursor::new(bytes);
+        let (schema, _) = infer_json_schema_from_seekable(&mut bytes_cursor, None)?;
+        let json_reader = ReaderBuilder::new(Arc::new(schema.clone())).build(bytes_cursor)?;
+        write_arrow_file(&location, &schema, stream::iter(json_reader)).await
+    }
+}
+
+/// Yields the bytes of each element of a top-level JSON array without parsing them
+struct ArrayElements<'a> {
+    bytes: &'a [u8],
+    /// Position after the `[` or the `,` preceding the next element
+    pos: usize,
+    done: bool,
+}
+
+impl<'a> ArrayElements<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        let start = bytes.iter().position(|b| *b == b'[').map_or(0, |i| i + 1);
+        let mut elements = ArrayElements {
+            bytes,
+            pos: start,
+            done: false,
+        };
+        elements.skip_whitespace();
+        if elements.bytes.get(elements.pos) == Some(&b']') {
+            elements.pos += 1;
+            elements.done = true;
+        }
+        elements
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .bytes
+            .get(self.pos)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn next_element(&mut self) -> Result<Option<&'a [u8]>, ArrowError> {
+        if self.done {
+            if self.bytes[self.pos..].iter().all(u8::is_ascii_whitespace) {
+                return Ok(None);
+            }
+            return Err(invalid_array("trailing characters after the array"));
+        }
+        self.skip_whitespace();
+        let start = self.pos;
+        let (mut depth, mut in_string, mut escaped) = (0usize, false, false);
+        while let Some(&b) = self.bytes.get(self.pos) {
+            if in_string {
+                match b {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+            } else {
+                match b {
+                    b'"' => in_string = true,
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' if depth > 0 => depth -= 1,
+                    b',' | b']' => {
+                        let end = self.bytes[start..self.pos]
+                            .iter()
+                            .rposition(|b| !b.is_ascii_whitespace())
+                            .map_or(start, |i| start + i + 1);
+                        let element = &self.bytes[start..end];
+                        if element.is_empty() {
+                            return Err(invalid_array("missing array element"));
+                        }
+                        self.done = b == b']';
+                        self.pos += 1;
+                        return Ok(Some(element));
+                    }
+                    _ => {}
+                }
+            }
+            self.pos += 1;
+        }
+        Err(invalid_array("unterminated array"))
+    }
+}
+
+impl<'a> Iterator for ArrayElements<'a> {
+    type Item = Result<&'a [u8], ArrowError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.next_element();
+        if next.is_err() {
+            // nothing sensible follows a malformed array
+            self.done = true;
+            self.pos = self.bytes.len();
+        }
+        next.transpose()
+    }
+}
+
+fn invalid_array(reason: &str) -> ArrowError {
+    ArrowError::JsonError(format!("Invalid JSON array: {reason}"))
+}
@@ -1,38 +1,52 @@
-mod opfs_store;
+mod catalog;
+mod control;
+mod csv_errors;
+mod csv_import;
+mod csv_sniff;
+pub mod error;
+mod explain;
+mod json_import;
+mod manifest;
+pub mod opfs_store;
+mod parquet_io;
+mod schema;
+pub mod session;
 pub mod web_fs_utils;
 
-use datafusion::arrow::array::RecordBatchWriter;
-use datafusion::arrow::datatypes::Schema;
-use datafusion::arrow::ipc::writer::FileWriter;
-use datafusion::arrow::ipc::writer::IpcWriteOptions;
-use datafusion::arrow::ipc::writer::StreamWriter;
-use datafusion::arrow::ipc::MetadataVersion;
-use datafusion::arrow::record_batch::RecordBatch;
-use datafusion::error::Result;
-use datafusion::execution::options::ArrowReadOptions;
-use datafusion::prelude::*;
-use datafusion::sql::TableReference;
-use js_sys::ArrayBuffer;
-use js_sys::Uint8Array;
+use csv_import::{cp_csv_source_to_arrow, cp_csv_to_arrow, preview_csv_source};
+use csv_sniff::{sniff, SNIFF_BYTES};
+use error::QueryError;
+use js_sys::{ArrayBuffer, Object, Uint8Array};
+use json_import::cp_json_to_arrow;
+use manifest::Manifest;
 use once_cell::sync::Lazy;
 use opfs_store::OpfsFileSystem;
-use web_fs_utils::{cp_csv_to_arrow, write_arrow_to_file};
+use parquet_io::cp_parquet_to_arrow;
+use session::{to_js, PreparedStatement, QueryEngine};
 use std::sync::Arc;
 use std::sync::OnceLock;
 use url::Url;
 use wasm_bindgen::prelude::*;
+use web_fs_utils::CsvSource;
 
-fn _opfs_url() -> &'static Box<Url> {
-    static OPFS_PREFIX: OnceLock<Box<Url>> = OnceLock::new();
-    OPFS_PREFIX.get_or_init(|| Box::new(Url::parse("opfs://").unwrap()))
+fn _opfs_url() -> &'static Url {
+    static OPFS_PREFIX: OnceLock<Url> = OnceLock::new();
+    OPFS_PREFIX.get_or_init(|| Url::parse("opfs://").unwrap())
 }
 
-static CTX: Lazy<SessionContext> = Lazy::new(|| {
-    let ctx = SessionContext::new();
-    let opfs_store: OpfsFileSystem = OpfsFileSystem::new();
-    ctx.register_object_store(_opfs_url().as_ref(), Arc::new(opfs_store));
-    ctx
-});
+// one store for all sessions, queries reporting progress read through a counting copy
+pub(crate) fn opfs_store() -> Arc<OpfsFileSystem> {
+    static OPFS_STORE: OnceLock<Arc<OpfsFileSystem>> = OnceLock::new();
+    OPFS_STORE
+        .get_or_init(|| Arc::new(OpfsFileSystem::new()))
+        .clone()
+}
+
+// session behind the free functions, for callers that don't need more than one. Its
+// tables are restored from the default manifest, `.catalog/default.json`, on first use,
+// and every table registered or removed through it saves the manifest again
+static CTX: Lazy<QueryEngine> =
+    Lazy::new(|| QueryEngine::with_manifest(Manifest::location("default")));
 
 #[wasm_bindgen]
 pub fn init_panic_hook() {
@@ -40,119 +54,177 @@ pub fn init_panic_hook() {
 }
 
 #[wasm_bindgen]
-pub async fn unegister_table(table_name: String) -> Result<(), JsError> {
-    let table_ref = TableReference::from(table_name);
-    CTX.deregister_table(table_ref).unwrap();
-    Ok(())
+pub async fn unegister_table(table_name: String) -> Result<(), QueryError> {
+    CTX.unregister_table(table_name).await
 }
 
+/// Converts the CSV to `<file_digest>.arrow` and returns the schema it was written with,
+/// the number of rows and the errors, see `on_error` of the config
 #[wasm_bindgen]
-pub async fn load_csv_bytes(file_uint8: ArrayBuffer, file_digest: String, csv_config: JsValue) -> Result<(), JsError> {
-    cp_csv_to_arrow(file_uint8, file_digest, csv_config).await.unwrap();
-    Ok(())
+pub async fn load_csv_bytes(
+    file_uint8: ArrayBuffer,
+    file_digest: String,
+    csv_config: JsValue,
+) -> Result<JsValue, QueryError> {
+    let import = cp_csv_to_arrow(file_uint8, file_digest, csv_config).await?;
+    to_js(&import)
 }
 
+/// Like `load_csv_bytes`, but reads the `File` or `Blob` in chunks instead of as one buffer
 #[wasm_bindgen]
-pub async fn register_table(file_digest: String, table_name: String) -> Result<(), JsError> { 
-    let ctx = &CTX;
-    let table_ref = TableReference::from(table_name.clone());
-    if !ctx.table_exist(table_ref).unwrap() {
-        let register_path = format!("opfs:///{file_digest}.arrow");
-        // register as table
-        ctx.register_arrow(
-            &table_name.as_str(),
-            register_path.as_str(),
-            ArrowReadOptions::default(),
-        )
-        .await?;
-    }
-    Ok(())
+pub async fn load_csv_file(
+    file: web_sys::Blob,
+    file_digest: String,
+    csv_config: JsValue,
+) -> Result<JsValue, QueryError> {
+    let import = cp_csv_source_to_arrow(CsvSource::Blob(file), file_digest, csv_config).await?;
+    to_js(&import)
+}
+
+/// Delimiter, quote, header, decimal separator and encoding guessed from the start of the
+/// CSV, the defaults of the empty `csv_config` fields of an import, and its empty trailing
+/// columns, which `trim_empty_columns` drops
+#[wasm_bindgen]
+pub fn sniff_csv(file_uint8: ArrayBuffer) -> Result<JsValue, QueryError> {
+    let bytes = Uint8Array::new(&file_uint8);
+    let sample = bytes
+        .subarray(0, bytes.length().min(SNIFF_BYTES as u32))
+        .to_vec();
+    to_js(&sniff(&sample, sample.len() == bytes.length() as usize))
+}
+
+/// Schema, the first `n_rows` rows as Arrow IPC stream, empty values per column and the
+/// values that do not parse, as `load_csv_bytes` would read them. Nothing is written.
+#[wasm_bindgen]
+pub async fn preview_csv(
+    file_uint8: ArrayBuffer,
+    csv_config: JsValue,
+    n_rows: usize,
+) -> Result<JsValue, QueryError> {
+    let source = CsvSource::Buffer(Uint8Array::new(&file_uint8));
+    let preview = preview_csv_source(source, csv_config, n_rows).await?;
+    to_js(&preview)
 }
 
+/// NDJSON or a top-level JSON array, converted to `<file_digest>.arrow`
 #[wasm_bindgen]
-pub async fn get_table_schema(table_name: String) -> Result<JsValue, JsError> { 
-    let table_ref = TableReference::from(table_name.clone());
-    let table = CTX.table(table_ref).await?;
-    let schema = Schema::from(table.schema());
-    let mut json_str = format!("{{\"{table_name}\":[");
-    let fields_len: i32 = schema.fields.len() as i32;
-    let mut count: i32 = 1;
-    for field in schema.fields() {
-        let name = field.name();        
-        let field_str = format!("{{\"label\":\"{name}\", \"type\":\"property\"}}");
-        json_str.push_str(&field_str);
-        if count < fields_len {
-            json_str.push_str(",");
-            count += 1;
-        }
-    }
-    json_str.push_str("]}");
-    Ok(JsValue::from(json_str))
-}
-
-#[wasm_bindgen]
-pub async fn register_csv(file_digest: String, table_name: String) -> Result<(), JsError> {
-    let ctx = &CTX;
-    let table_ref = TableReference::from(table_name.clone());
-    if !ctx.table_exist(table_ref.clone()).unwrap() {
-        let register_path = format!("opfs:///{file_digest}.csv");
-        // register CSV as table
-        ctx.register_csv(
-            table_ref,
-            register_path.as_str(),
-            CsvReadOptions::new(),
-        )
-        .await?;
-    }
+pub async fn load_json_bytes(
+    file_uint8: ArrayBuffer,
+    file_digest: String,
+) -> Result<(), QueryError> {
+    cp_json_to_arrow(file_uint8, file_digest).await?;
     Ok(())
 }
 
+/// Parquet, converted to `<file_digest>.arrow`
+#[wasm_bindgen]
+pub async fn load_parquet_bytes(
+    file_uint8: ArrayBuffer,
+    file_digest: String,
+) -> Result<(), QueryError> {
+    cp_parquet_to_arrow(file_uint8, file_digest).await
+}
+
+#[wasm_bindgen]
+pub async fn register_table(file_digest: String, table_name: String) -> Result<(), QueryError> {
+    CTX.register_table(file_digest, table_name).await
+}
+
+#[wasm_bindgen]
+pub async fn get_table_schema(table_name: String) -> Result<JsValue, QueryError> {
+    CTX.get_table_schema(table_name).await
+}
+
+#[wasm_bindgen]
+pub async fn get_table_completions(table_name: String) -> Result<JsValue, QueryError> {
+    CTX.get_table_completions(table_name).await
+}
+
+#[wasm_bindgen]
+pub fn list_catalogs() -> Vec<String> {
+    CTX.list_catalogs()
+}
+
+#[wasm_bindgen]
+pub fn list_schemas(catalog: Option<String>) -> Result<JsValue, QueryError> {
+    CTX.list_schemas(catalog)
+}
+
+#[wasm_bindgen]
+pub async fn list_tables(
+    catalog: Option<String>,
+    schema: Option<String>,
+) -> Result<JsValue, QueryError> {
+    CTX.list_tables(catalog, schema).await
+}
+
+#[wasm_bindgen]
+pub fn list_functions() -> Result<JsValue, QueryError> {
+    CTX.list_functions()
+}
+
+#[wasm_bindgen]
+pub async fn register_csv(
+    file_digest: String,
+    table_name: String,
+    csv_config: Option<JsValue>,
+) -> Result<(), QueryError> {
+    CTX.register_csv(file_digest, table_name, csv_config).await
+}
+
+#[wasm_bindgen]
+pub async fn register_json(file_digest: String, table_name: String) -> Result<(), QueryError> {
+    CTX.register_json(file_digest, table_name).await
+}
+
+#[wasm_bindgen]
+pub async fn register_parquet(file_digest: String, table_name: String) -> Result<(), QueryError> {
+    CTX.register_parquet(file_digest, table_name).await
+}
+
+#[wasm_bindgen]
+pub async fn run_sql(sql_query: String, options: Option<Object>) -> Result<JsValue, QueryError> {
+    CTX.run_sql(sql_query, options).await
+}
+
+#[wasm_bindgen]
+pub async fn run_sql_stream(
+    sql_query: String,
+    options: Option<Object>,
+) -> Result<web_sys::ReadableStream, QueryError> {
+    CTX.run_sql_stream(sql_query, options).await
+}
+
 #[wasm_bindgen]
-pub async fn run_sql(sql_query: String) -> Result<JsValue, JsError> {
-    // create a plan to run a SQL query
-    let df = CTX.sql(&sql_query.as_str()).await?;
-    let schema = Schema::from(df.schema());
-    // execute the plan and collect the results as Vec<RecordBatch>
-    let results: Vec<RecordBatch> = df.collect().await?;
+pub async fn prepare(sql_query: String) -> Result<PreparedStatement, QueryError> {
+    CTX.prepare(sql_query).await
+}
 
-    // serialize to in memory vector
-    let mut output: Vec<u8> = Vec::new();
+#[wasm_bindgen]
+pub async fn execute(
+    stmt: &PreparedStatement,
+    params: JsValue,
+    options: Option<Object>,
+) -> Result<JsValue, QueryError> {
+    stmt.execute(params, options).await
+}
 
-    let options = IpcWriteOptions::try_new(8, false, MetadataVersion::V5)?;
-    let mut writer = StreamWriter::try_new_with_options(&mut output, &schema, options).unwrap();
-    for batch in results {
-        writer.write(&batch).unwrap();
-    }
-    writer.close().unwrap();
-    
-    let js_arr = Uint8Array::from(&output[..]);
-    Ok(JsValue::from(&js_arr))
+#[wasm_bindgen]
+pub async fn explain_sql(sql_query: String, options: JsValue) -> Result<JsValue, QueryError> {
+    CTX.explain_sql(sql_query, options).await
 }
 // interesting option to persist the result of a SQL query to a file
 // pub async fn persist_sql(sql_query: String, file_name: String) -> Result<(), JsError> {
 //     // create a plan to run a SQL query
-//     let df = CTX.sql(&sql_query.as_str()).await?;
+//     let df = CTX.sql(sql_query.as_str()).await?;
 //     df.write_table(file_name.as_str(), DataFrameWriteOptions::new()).await?;
 //     Ok(())
 // }
 #[wasm_bindgen]
-pub async fn persist_sql(sql_query: String, file_name: String) -> Result<(), JsError> {
-    // create a plan to run a SQL query
-    let df = CTX.sql(&sql_query.as_str()).await?;
-    let schema = Schema::from(df.schema());
-    // execute the plan and collect the results as Vec<RecordBatch>
-    let results: Vec<RecordBatch> = df.collect().await?;
-
-    // serialize to in memory vector
-    let mut output: Vec<u8> = Vec::new();
-
-    let options = IpcWriteOptions::try_new(8, false, MetadataVersion::V5)?;
-    let mut writer = FileWriter::try_new_with_options(&mut output, &schema, options).unwrap();
-    for batch in results {
-        writer.write(&batch).unwrap();
-    }
-    writer.close().unwrap();
-    write_arrow_to_file(output, file_name).await;
-    Ok(())
+pub async fn persist_sql(
+    sql_query: String,
+    file_name: String,
+    options: JsValue,
+) -> Result<(), QueryError> {
+    CTX.persist_sql(sql_query, file_name, options).await
 }
-
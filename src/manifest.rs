@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+
+use arrow_schema::Schema;
+use object_store::path::Path;
+use object_store::{ObjectStore, PutMode, PutPayload, UpdateVersion};
+use serde::{Deserialize, Serialize};
+use web_sys::console;
+
+use crate::csv_import::CsvConfig;
+use crate::error::{ErrorKind, QueryError};
+use crate::opfs_store;
+
+const MANIFEST_VERSION: u32 = 1;
+
+/// Folder of the manifests in the data folder, left out of its listings
+pub(crate) const CATALOG_FOLDER: &str = ".catalog";
+
+/// Tables registered through the exports of a session, stored as JSON in the
+/// [`CATALOG_FOLDER`] so they can be registered again after a reload
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    version: u32,
+    pub tables: BTreeMap<String, ManifestTable>,
+    /// ETag of the file as loaded or saved last, `None` if there was none
+    #[serde(skip)]
+    e_tag: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct ManifestTable {
+    /// Name of the data file without extension
+    pub digest: String,
+    pub format: TableFormat,
+    #[serde(default)]
+    pub csv: CsvTableOptions,
+    /// Schema at registration, used instead of inferring it again
+    pub schema: Option<Schema>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TableFormat {
+    Arrow,
+    Csv,
+    /// Newline delimited JSON
+    Json,
+    Parquet,
+}
+
+impl TableFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TableFormat::Arrow => "arrow",
+            TableFormat::Csv => "csv",
+            TableFormat::Json => "json",
+            TableFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// Read options of CSV tables
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct CsvTableOptions {
+    pub has_header: bool,
+    pub delimiter: char,
+    pub quote: char,
+    pub escape: Option<char>,
+    pub comment: Option<char>,
+}
+
+impl Default for CsvTableOptions {
+    fn default() -> Self {
+        CsvTableOptions {
+            has_header: true,
+            delimiter: ',',
+            quote: '"',
+            escape: None,
+            comment: None,
+        }
+    }
+}
+
+impl CsvTableOptions {
+    /// The read options set in `cfg`, the others keep their defaults
+    pub fn from_config(cfg: &CsvConfig) -> CsvTableOptions {
+        let single = |s: &str| {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Some(c),
+                _ => None,
+            }
+        };
+        CsvTableOptions {
+            has_header: cfg.has_header.unwrap_or(true),
+            delimiter: single(&cfg.delimiter).unwrap_or(','),
+            quote: single(&cfg.quote).unwrap_or('"'),
+            escape: single(&cfg.escape),
+            comment: single(&cfg.comment),
+        }
+    }
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            tables: BTreeMap::new(),
+            e_tag: None,
+        }
+    }
+}
+
+impl Manifest {
+    /// Where the manifest of `workspace` is stored
+    pub fn location(workspace: &str) -> Path {
+        Path::from(CATALOG_FOLDER).child(format!("{workspace}.json"))
+    }
+
+    /// Reads the manifest at `location`, a missing file is an empty manifest
+    pub async fn load(location: &Path) -> Result<Manifest, QueryError> {
+        let (bytes, e_tag) = match opfs_store().get(location).await {
+            Ok(result) => {
+                let e_tag = result.meta.e_tag.clone();
+                (result.bytes().await?, e_tag)
+            }
+            Err(object_store::Error::NotFound { .. }) => return Ok(Manifest::default()),
+            Err(error) => return Err(error.into()),
+        };
+        let mut manifest: Manifest = serde_json::from_slice(&bytes).map_err(|e| {
+            QueryError::new(
+                ErrorKind::Io,
+                format!("Invalid catalog manifest {location}: {e}"),
+            )
+        })?;
+        if manifest.version > MANIFEST_VERSION {
+            return Err(QueryError::new(
+                ErrorKind::Io,
+                format!(
+                    "Catalog manifest {location} has the unknown version {}",
+                    manifest.version
+                ),
+            ));
+        }
+        manifest.e_tag = e_tag;
+        Ok(manifest)
+    }
+
+    /// Writes the manifest to `location` unless another session changed the file since
+    /// it was loaded or saved, returns `false` then
+    pub async fn save(&mut self, location: &Path) -> Result<bool, QueryError> {
+        let json = serde_json::to_vec_pretty(self)
+            .map_err(|e| QueryError::new(ErrorKind::Execution, e.to_string()))?;
+        let mode = match &self.e_tag {
+            Some(e_tag) => PutMode::Update(UpdateVersion {
+                e_tag: Some(e_tag.clone()),
+                version: None,
+            }),
+            None => PutMode::Create,
+        };
+        let result = opfs_store()
+            .put_opts(location, PutPayload::from(json), mode.into())
+            .await;
+        match result {
+            Ok(result) => {
+                self.e_tag = result.e_tag;
+                Ok(true)
+            }
+            Err(object_store::Error::Precondition { .. })
+            | Err(object_store::Error::AlreadyExists { .. }) => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Moves a manifest that cannot be read to `<location>.invalid`, so saving the
+    /// manifest again does not overwrite what is left of it
+    pub async fn set_aside(location: &Path) {
+        let aside = Path::from(format!("{location}.invalid"));
+        if let Err(error) = opfs_store().rename(location, &aside).await {
+            console::warn_1(&format!("Could not move {location} to {aside}: {error}").into());
+        }
+    }
+}
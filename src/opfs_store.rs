@@ -1,34 +1,171 @@
 use async_trait::async_trait;
-use futures::channel::oneshot;
+use bytes::Bytes;
+use futures::channel::{mpsc, oneshot};
+use futures::lock::{Mutex as AsyncMutex, OwnedMutexGuard};
 use futures::stream::{BoxStream, StreamExt};
-use object_store::GetRange;
+use futures::FutureExt;
+use js_sys::Reflect;
 use object_store::{
     path::Path, Attributes, Error, GetOptions, GetResult, GetResultPayload, ListResult,
-    MultipartUpload, ObjectMeta, ObjectStore, PutMultipartOpts, PutOptions, PutPayload, PutResult,
-    Result,
+    MultipartUpload, ObjectMeta, ObjectStore, PutMode, PutMultipartOptions, PutOptions, PutPayload,
+    PutResult, Result, UploadPart,
 };
-use snafu::{ResultExt, Snafu};
-use std::sync::mpsc;
+use object_store::{GetRange, OBJECT_STORE_COALESCE_DEFAULT};
+use once_cell::sync::Lazy;
+use snafu::{OptionExt, Snafu};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use wasm_bindgen::JsValue;
 
-use crate::web_fs_utils::{get_file_data, get_files, FileResponse};
+use crate::web_fs_utils::{
+    copy_file, delete_file, get_file_data, get_files, get_folder_entries, open_file_writer,
+    put_file_data, stream_file_data, FileResponse, FolderResponse, WriterRequest,
+};
+
+/// Number of chunks or listing entries produced ahead of the consumer of a stream
+const STREAM_BUFFER: usize = 2;
+
+/// Locks of the paths being written, shared by all stores of this module. OPFS has no
+/// conditional writes, so `put_opts`, `copy` and `rename` hold the lock of their target
+/// from the check of the existing file to the end of the write, `copy` and `rename` that
+/// of their source and `delete` that of the removed file as well. Writers in other tabs
+/// or workers, and multipart uploads, do not take it.
+static PATH_LOCKS: Lazy<Mutex<HashMap<Path, Weak<AsyncMutex<()>>>>> = Lazy::new(Default::default);
+
+/// Waits for the lock of `location`, held until the guard is dropped
+async fn lock_path(location: &Path) -> OwnedMutexGuard<()> {
+    let lock = {
+        let mut locks = PATH_LOCKS.lock().unwrap_or_else(PoisonError::into_inner);
+        locks.retain(|_, lock| lock.strong_count() > 0);
+        match locks.get(location).and_then(Weak::upgrade) {
+            Some(lock) => lock,
+            None => {
+                let lock = Arc::new(AsyncMutex::new(()));
+                locks.insert(location.clone(), Arc::downgrade(&lock));
+                lock
+            }
+        }
+    };
+    lock.lock_owned().await
+}
+
+/// Waits for the locks of both paths of a copy or rename, always taken in the same order
+/// so two transfers between the same files cannot wait for each other
+async fn lock_paths(from: &Path, to: &Path) -> Vec<OwnedMutexGuard<()>> {
+    if from == to {
+        return vec![lock_path(to).await];
+    }
+    let (first, second) = if from.as_ref() < to.as_ref() {
+        (from, to)
+    } else {
+        (to, from)
+    };
+    vec![lock_path(first).await, lock_path(second).await]
+}
+
+/// Writes made through the stores of this module per path. The count is part of the ETag,
+/// so two writes of the same size within one millisecond still get different ETags.
+/// Writes from other tabs or workers are not counted.
+static WRITE_COUNTS: Lazy<Mutex<HashMap<Path, u64>>> = Lazy::new(Default::default);
+
+/// Counts a write to, or the removal of, the file at `location`. Failed writes count too,
+/// as they may have changed the file.
+fn record_write(location: &Path) {
+    let mut counts = WRITE_COUNTS.lock().unwrap_or_else(PoisonError::into_inner);
+    *counts.entry(location.clone()).or_default() += 1;
+}
+
+#[derive(Debug, Clone, Snafu)]
+pub(crate) enum OpfsError {
+    #[snafu(display("Invalid range for {path}: {message}"))]
+    Range { path: String, message: String },
+
+    #[snafu(display("File not found: {path}"))]
+    NotFound { path: String },
+
+    #[snafu(display("File already exists: {path}"))]
+    AlreadyExists { path: String },
 
-#[derive(Debug, Snafu)]
-pub(crate) enum InvalidGetRange {
-    #[snafu(display(
-        "Wanted range starting at {requested}, but object was only {length} bytes long"
-    ))]
-    StartTooLarge { requested: usize, length: usize },
+    #[snafu(display("Precondition failed for {path}: {message}"))]
+    Precondition { path: String, message: String },
+
+    #[snafu(display("ETag required for conditional update"))]
+    MissingETag,
+
+    #[snafu(display("Upload to {path} is already completed or aborted"))]
+    UploadClosed { path: String },
+
+    #[snafu(display("Permission denied for {path}: {message}"))]
+    PermissionDenied { path: String, message: String },
+
+    #[snafu(display("Storage quota exceeded while writing {path}: {message}"))]
+    QuotaExceeded { path: String, message: String },
+
+    #[snafu(display("{path} is not a file: {message}"))]
+    TypeMismatch { path: String, message: String },
+
+    #[snafu(display("{name} raised for {path}: {message}"))]
+    JsException {
+        path: String,
+        name: String,
+        message: String,
+    },
+
+    #[snafu(display("OPFS task for {path} ended without a result"))]
+    Cancelled { path: String },
 }
 
-#[derive(Debug, Snafu)]
-enum OpfsError {
-    #[snafu(display("Invalid range: {source}"))]
-    Range { source: InvalidGetRange },
+impl OpfsError {
+    /// Classifies a rejected promise or thrown exception by its `DOMException` name
+    pub(crate) fn from_js(location: &Path, error: JsValue) -> Self {
+        let property = |key: &str| {
+            Reflect::get(&error, &JsValue::from_str(key))
+                .ok()
+                .and_then(|value| value.as_string())
+        };
+        let path = location.to_string();
+        let name = property("name").unwrap_or_else(|| "Error".to_string());
+        let message = property("message")
+            .or_else(|| error.as_string())
+            .unwrap_or_default();
+        match name.as_str() {
+            "NotFoundError" => OpfsError::NotFound { path },
+            "NotAllowedError" | "SecurityError" => OpfsError::PermissionDenied { path, message },
+            "QuotaExceededError" => OpfsError::QuotaExceeded { path, message },
+            "TypeMismatchError" => OpfsError::TypeMismatch { path, message },
+            _ => OpfsError::JsException {
+                path,
+                name,
+                message,
+            },
+        }
+    }
 }
 
 impl From<OpfsError> for object_store::Error {
     fn from(source: OpfsError) -> Self {
         match source {
+            // a folder is not an object, so finding one counts as not found
+            OpfsError::NotFound { ref path } | OpfsError::TypeMismatch { ref path, .. } => {
+                Error::NotFound {
+                    path: path.clone(),
+                    source: Box::new(source),
+                }
+            }
+            OpfsError::PermissionDenied { ref path, .. } => Error::PermissionDenied {
+                path: path.clone(),
+                source: Box::new(source),
+            },
+            OpfsError::AlreadyExists { ref path } => Error::AlreadyExists {
+                path: path.clone(),
+                source: Box::new(source),
+            },
+            OpfsError::Precondition { ref path, .. } => Error::Precondition {
+                path: path.clone(),
+                source: Box::new(source),
+            },
             _ => Error::Generic {
                 store: "OpfsFileSystem",
                 source: Box::new(source),
@@ -37,134 +174,334 @@ impl From<OpfsError> for object_store::Error {
     }
 }
 
+/// Resolves `range` against a file of `len` bytes, inverted and out of bounds ranges fail
+fn resolve_range(location: &Path, range: &GetRange, len: u64) -> Result<Range<u64>> {
+    range.as_range(len).map_err(|e| {
+        OpfsError::Range {
+            path: location.to_string(),
+            message: e.to_string(),
+        }
+        .into()
+    })
+}
+
+// Copied from object_store::util, returns a sorted list of ranges that cover `ranges`
+fn merge_ranges(ranges: &[Range<u64>], coalesce: u64) -> Vec<Range<u64>> {
+    if ranges.is_empty() {
+        return vec![];
+    }
+
+    let mut ranges = ranges.to_vec();
+    ranges.sort_unstable_by_key(|range| range.start);
+
+    let mut ret = Vec::with_capacity(ranges.len());
+    let mut start_idx = 0;
+    let mut end_idx = 1;
+
+    while start_idx != ranges.len() {
+        let mut range_end = ranges[start_idx].end;
+
+        while end_idx != ranges.len()
+            && ranges[end_idx]
+                .start
+                .checked_sub(range_end)
+                .map(|delta| delta <= coalesce)
+                .unwrap_or(true)
+        {
+            range_end = range_end.max(ranges[end_idx].end);
+            end_idx += 1;
+        }
+
+        ret.push(ranges[start_idx].start..range_end);
+
+        start_idx = end_idx;
+        end_idx += 1;
+    }
+
+    ret
+}
+
+/// OPFS has no native ETag, so one is derived from the modification time, the size and
+/// the number of writes to `location` recorded by this module
+fn get_etag(location: &Path, response: &FileResponse) -> String {
+    let writes = WRITE_COUNTS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(location)
+        .copied()
+        .unwrap_or_default();
+    format!(
+        "{:x}-{:x}-{:x}",
+        response.last_modified.timestamp_millis(),
+        response.size,
+        writes
+    )
+}
+
+/// Waits for the result of a task spawned by one of the `web_fs_utils` functions
+async fn receive<T>(rx: oneshot::Receiver<Result<T, OpfsError>>, location: &Path) -> Result<T> {
+    let result = rx.await.map_err(|_| OpfsError::Cancelled {
+        path: location.to_string(),
+    })?;
+    Ok(result?)
+}
+
+fn convert_metadata(location: &Path, response: &FileResponse) -> ObjectMeta {
+    ObjectMeta {
+        location: location.clone(),
+        last_modified: response.last_modified,
+        size: response.size,
+        e_tag: Some(get_etag(location, response)),
+        version: None,
+    }
+}
+
 impl std::fmt::Display for OpfsFileSystem {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "OpfsFileSystem()")
     }
 }
 #[derive(Debug, Default)]
-pub struct OpfsFileSystem {}
+pub struct OpfsFileSystem {
+    /// Counter of the bytes handed out by `get_opts` and `get_ranges`, if any
+    bytes_read: Option<Arc<AtomicU64>>,
+}
 
 #[async_trait]
 impl ObjectStore for OpfsFileSystem {
-    async fn put_opts(&self, _: &Path, _: PutPayload, _: PutOptions) -> Result<PutResult> {
-        Err(Error::NotImplemented)
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> Result<PutResult> {
+        let _lock = lock_path(location).await;
+        match opts.mode {
+            PutMode::Overwrite => {}
+            PutMode::Create => self.ensure_absent(location).await?,
+            PutMode::Update(version) => {
+                let existing = match self.head(location).await {
+                    Ok(meta) => meta.e_tag.unwrap_or_default(),
+                    Err(Error::NotFound { .. }) => {
+                        return Err(OpfsError::Precondition {
+                            path: location.to_string(),
+                            message: "file not found".to_string(),
+                        }
+                        .into())
+                    }
+                    Err(e) => return Err(e),
+                };
+                let expected = version.e_tag.context(MissingETagSnafu)?;
+                if existing != expected {
+                    return Err(OpfsError::Precondition {
+                        path: location.to_string(),
+                        message: format!("{existing} does not match {expected}"),
+                    }
+                    .into());
+                }
+            }
+        }
+
+        let (tx, rx) = oneshot::channel::<Result<FileResponse, OpfsError>>();
+        put_file_data(tx, location.clone(), payload);
+        let response = receive(rx, location).await;
+        record_write(location);
+        let response = response?;
+        Ok(PutResult {
+            e_tag: Some(get_etag(location, &response)),
+            version: None,
+        })
     }
 
     async fn put_multipart_opts(
         &self,
-        _: &Path,
-        _: PutMultipartOpts,
+        location: &Path,
+        _: PutMultipartOptions,
     ) -> Result<Box<dyn MultipartUpload>> {
-        Err(Error::Generic {
-            store: "put_multipart_opts",
-            source: Box::new(Error::NotImplemented),
-        })
+        let (tx, rx) = mpsc::unbounded::<WriterRequest>();
+        open_file_writer(rx, location.clone());
+        Ok(Box::new(OpfsMultipartUpload {
+            location: location.clone(),
+            tx,
+        }))
     }
 
-    async fn head(&self, location: &Path) -> Result<ObjectMeta> { 
-        let loc_string = location.to_string();
-        let (tx, rx) = oneshot::channel::<FileResponse>();
-        get_file_data(tx, loc_string.to_owned(), true);
-        let response = rx.await.unwrap();
-        Ok(ObjectMeta {
-            location: location.clone(),
-            last_modified: response.last_modified,
-            size: response.size,
-            e_tag: Some(response.name),
-            version: None,
-        })
+    async fn head(&self, location: &Path) -> Result<ObjectMeta> {
+        let (tx, rx) = oneshot::channel::<Result<FileResponse, OpfsError>>();
+        get_file_data(tx, location.clone(), Vec::new());
+        let response = receive(rx, location).await?;
+        Ok(convert_metadata(location, &response))
     }
 
     async fn get_opts(&self, location: &Path, options: GetOptions) -> Result<GetResult> {
-        let loc_string = location.to_string();
-        let (tx, rx) = oneshot::channel::<FileResponse>();
-        get_file_data(tx, loc_string, false);
-        let response = rx.await.unwrap();
+        let (tx, rx) = oneshot::channel::<Result<FileResponse, OpfsError>>();
+        let (chunk_tx, chunk_rx) = mpsc::channel::<Result<Bytes, OpfsError>>(STREAM_BUFFER);
+        if options.head {
+            // `chunk_tx` is dropped unused, so the payload is empty
+            get_file_data(tx, location.clone(), Vec::new());
+        } else {
+            stream_file_data(tx, chunk_tx, location.clone(), options.range.clone());
+        }
+        let response = receive(rx, location).await?;
 
-        let meta: ObjectMeta = ObjectMeta {
-            location: location.clone(),
-            last_modified: response.last_modified,
-            size: response.size,
-            e_tag: Some(response.name),
-            version: None,
-        };
+        let meta: ObjectMeta = convert_metadata(location, &response);
+        // dropping `chunk_rx` on failure stops the stream
+        options.check_preconditions(&meta)?;
 
-        let bytes: bytes::Bytes = response.bytes.unwrap();
-        // Copied from GetRange
-        let (range, data) = match options.range {
-            Some(range) => {
-                let len = bytes.len() as u64;
-                let r = (match range {
-                    GetRange::Bounded(r) => {
-                        if r.start >= len {
-                            Err(InvalidGetRange::StartTooLarge {
-                                requested: r.start as usize,
-                                length: len as usize,
-                            })
-                        } else if r.end > len {
-                            Ok(r.start..len)
-                        } else {
-                            Ok(r.clone())
-                        }
-                    }
-                    GetRange::Offset(o) => {
-                        if o >= len {
-                            Err(InvalidGetRange::StartTooLarge {
-                                requested: o as usize,
-                                length: len as usize,
-                            })
-                        } else {
-                            Ok(o..len)
-                        }
-                    }
-                    GetRange::Suffix(n) => Ok(len.saturating_sub(n)..len),
-                })
-                .context(RangeSnafu)?;
-                (r.clone(), bytes.slice(r.start as usize..r.end as usize))
-            }
-            None => (0..bytes.len() as u64, bytes),
+        let range = match options.range {
+            Some(range) => resolve_range(location, &range, response.size)?,
+            None => 0..response.size,
         };
-        let stream = futures::stream::once(futures::future::ready(Ok(data)));
+        let bytes_read = self.bytes_read.clone();
+        let payload = chunk_rx.map(move |chunk| {
+            let chunk = chunk?;
+            if let Some(bytes_read) = &bytes_read {
+                bytes_read.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+            Ok(chunk)
+        });
         Ok(GetResult {
-            payload: GetResultPayload::Stream(stream.boxed()),
+            payload: GetResultPayload::Stream(payload.boxed()),
             attributes: Attributes::default(),
             meta,
             range,
         })
     }
-    async fn delete(&self, _: &Path) -> Result<()> {
-        return Err(Error::Generic {
-            store: "delete",
-            source: Box::new(Error::NotImplemented),
-        });
+
+    async fn get_ranges(&self, location: &Path, ranges: &[Range<u64>]) -> Result<Vec<Bytes>> {
+        let fetch_ranges = merge_ranges(ranges, OBJECT_STORE_COALESCE_DEFAULT);
+        let (tx, rx) = oneshot::channel::<Result<FileResponse, OpfsError>>();
+        get_file_data(
+            tx,
+            location.clone(),
+            fetch_ranges
+                .iter()
+                .cloned()
+                .map(GetRange::Bounded)
+                .collect(),
+        );
+        let response = receive(rx, location).await?;
+        if let Some(bytes_read) = &self.bytes_read {
+            let fetched: usize = response.bytes.iter().map(Bytes::len).sum();
+            bytes_read.fetch_add(fetched as u64, Ordering::Relaxed);
+        }
+
+        ranges
+            .iter()
+            .map(|range| {
+                let range =
+                    resolve_range(location, &GetRange::Bounded(range.clone()), response.size)?;
+                let idx = fetch_ranges.partition_point(|v| v.start <= range.start) - 1;
+                let fetch_range = &fetch_ranges[idx];
+                let fetch_bytes = &response.bytes[idx];
+
+                let start = range.start - fetch_range.start;
+                let end = range.end - fetch_range.start;
+                Ok(fetch_bytes.slice(start as usize..(end as usize).min(fetch_bytes.len())))
+            })
+            .collect()
     }
-    fn list(&self, _: Option<&Path>) -> BoxStream<'static, Result<ObjectMeta>> {
-        let (tx, rx) = mpsc::channel::<ObjectMeta>();
 
-        get_files(tx);
+    async fn delete(&self, location: &Path) -> Result<()> {
+        let _lock = lock_path(location).await;
+        let (tx, rx) = oneshot::channel::<Result<(), OpfsError>>();
+        delete_file(tx, location.clone());
+        let result = receive(rx, location).await;
+        record_write(location);
+        result
+    }
 
-        let s: Vec<_> = rx.into_iter().map(|meta| Ok(meta)).collect();
-        futures::stream::iter(s).boxed()
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'static, Result<ObjectMeta>> {
+        let (tx, rx) = mpsc::channel::<Result<(Path, FileResponse), OpfsError>>(STREAM_BUFFER);
+        get_files(tx, prefix.cloned());
+        rx.map(|entry| {
+            let (location, response) = entry?;
+            Ok(convert_metadata(&location, &response))
+        })
+        .boxed()
     }
 
-    async fn list_with_delimiter(&self, _: Option<&Path>) -> Result<ListResult> {
-        return Err(Error::Generic {
-            store: "list_with_delimiter",
-            source: Box::new(Error::NotImplemented),
-        });
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
+        let prefix = prefix.cloned().unwrap_or_default();
+        let (tx, rx) = oneshot::channel::<Result<FolderResponse, OpfsError>>();
+        get_folder_entries(tx, prefix.clone());
+        let response = receive(rx, &prefix).await?;
+        Ok(ListResult {
+            common_prefixes: response.folders,
+            objects: response
+                .files
+                .iter()
+                .map(|(location, file)| convert_metadata(location, file))
+                .collect(),
+        })
     }
-    async fn copy(&self, _: &Path, _: &Path) -> Result<()> {
-        return Err(Error::Generic {
-            store: "copy",
-            source: Box::new(Error::NotImplemented),
-        });
+
+    async fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        let _locks = lock_paths(from, to).await;
+        self.copy_file(from, to, false).await
     }
-    async fn copy_if_not_exists(&self, _: &Path, _: &Path) -> Result<()> {
-        return Err(Error::Generic {
-            store: "copy_if_not_exists",
-            source: Box::new(Error::NotImplemented),
-        });
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+        let _locks = lock_paths(from, to).await;
+        self.ensure_absent(to).await?;
+        self.copy_file(from, to, false).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let _locks = lock_paths(from, to).await;
+        self.copy_file(from, to, true).await
+    }
+
+    async fn rename_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+        let _locks = lock_paths(from, to).await;
+        self.ensure_absent(to).await?;
+        self.copy_file(from, to, true).await
+    }
+}
+
+/// Streams the parts of an upload into a single `FileSystemWritableFileStream` on a file in
+/// a hidden folder, which replaces the target on `complete`. Parts are written in the order
+/// `put_part` is called.
+#[derive(Debug)]
+struct OpfsMultipartUpload {
+    location: Path,
+    tx: mpsc::UnboundedSender<WriterRequest>,
+}
+
+impl OpfsMultipartUpload {
+    fn closed(&self) -> OpfsError {
+        OpfsError::UploadClosed {
+            path: self.location.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl MultipartUpload for OpfsMultipartUpload {
+    fn put_part(&mut self, data: PutPayload) -> UploadPart {
+        let (ack, rx) = oneshot::channel::<Result<(), OpfsError>>();
+        // a failed send drops `ack`, which surfaces below as a cancelled receiver
+        let _ = self.tx.unbounded_send(WriterRequest::Part(data, ack));
+        let closed = self.closed();
+        async move { Ok(rx.await.map_err(|_| closed)??) }.boxed()
+    }
+
+    async fn complete(&mut self) -> Result<PutResult> {
+        let (tx, rx) = oneshot::channel::<Result<FileResponse, OpfsError>>();
+        let _ = self.tx.unbounded_send(WriterRequest::Complete(tx));
+        let response = rx.await.map_err(|_| self.closed())?;
+        record_write(&self.location);
+        let response = response?;
+        Ok(PutResult {
+            e_tag: Some(get_etag(&self.location, &response)),
+            version: None,
+        })
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        let (ack, rx) = oneshot::channel::<Result<(), OpfsError>>();
+        let _ = self.tx.unbounded_send(WriterRequest::Abort(ack));
+        Ok(rx.await.map_err(|_| self.closed())??)
     }
 }
 
@@ -173,4 +510,36 @@ impl OpfsFileSystem {
     pub fn new() -> OpfsFileSystem {
         Self::default()
     }
+
+    /// A store on the same files that adds the bytes read through it to `bytes_read`,
+    /// so a query can count its own reads
+    pub fn counting(&self, bytes_read: Arc<AtomicU64>) -> OpfsFileSystem {
+        OpfsFileSystem {
+            bytes_read: Some(bytes_read),
+        }
+    }
+
+    /// Fails with [`Error::AlreadyExists`] if there is a file at `location`, callers hold
+    /// the lock of `location` until they have written it
+    async fn ensure_absent(&self, location: &Path) -> Result<()> {
+        match self.head(location).await {
+            Ok(_) => Err(OpfsError::AlreadyExists {
+                path: location.to_string(),
+            }
+            .into()),
+            Err(Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn copy_file(&self, from: &Path, to: &Path, rename: bool) -> Result<()> {
+        let (tx, rx) = oneshot::channel::<Result<(), OpfsError>>();
+        copy_file(tx, from.clone(), to.clone(), rename);
+        let result = receive(rx, from).await;
+        record_write(to);
+        if rename {
+            record_write(from);
+        }
+        result
+    }
 }
@@ -0,0 +1,126 @@
+use std::str::FromStr;
+
+use bytes::Bytes;
+use datafusion::arrow::record_batch::RecordBatchReader;
+use datafusion::execution::SendableRecordBatchStream;
+use datafusion::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use datafusion::parquet::arrow::ArrowWriter;
+use datafusion::parquet::basic::Compression;
+use datafusion::parquet::file::properties::WriterProperties;
+use futures::{stream, StreamExt};
+use js_sys::{ArrayBuffer, Uint8Array};
+use object_store::path::Path;
+use object_store::{MultipartUpload, ObjectStore, PutPayload};
+use serde::Deserialize;
+
+use crate::csv_import::write_arrow_file;
+use crate::error::{ErrorKind, QueryError};
+use crate::opfs_store;
+
+/// Options of `persist_sql`,
+/// `{ format?: "arrow" | "parquet", compression?: string, row_group_size?: number }`
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct PersistOptions {
+    pub format: PersistFormat,
+    /// Parquet codec, `uncompressed`, `snappy`, `gzip(<level>)`, `brotli(<level>)`, `lz4`,
+    /// `lz4_raw` or `zstd(<level>)`, the writer's default if missing
+    pub compression: Option<String>,
+    /// Maximum rows per Parquet row group
+    pub row_group_size: Option<usize>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PersistFormat {
+    /// Arrow IPC file, `<file_name>.arrow`
+    #[default]
+    Arrow,
+    /// `<file_name>.parquet`
+    Parquet,
+}
+
+impl PersistOptions {
+    /// Writer properties with the codec and row group size of the options
+    pub fn writer_properties(&self) -> Result<WriterProperties, QueryError> {
+        let mut builder = WriterProperties::builder();
+        if let Some(compression) = &self.compression {
+            let compression = Compression::from_str(compression).map_err(|e| {
+                QueryError::new(
+                    ErrorKind::Parse,
+                    format!("Invalid options: compression {compression}: {e}"),
+                )
+            })?;
+            builder = builder.set_compression(compression);
+        }
+        if let Some(row_group_size) = self.row_group_size {
+            if row_group_size == 0 {
+                return Err(QueryError::new(
+                    ErrorKind::Parse,
+                    "Invalid options: row_group_size must be at least 1",
+                ));
+            }
+            builder = builder.set_max_row_group_size(row_group_size);
+        }
+        Ok(builder.build())
+    }
+}
+
+/// Converts the Parquet file to `<name>.arrow`, every batch is written to the file as
+/// soon as it is decoded
+pub async fn cp_parquet_to_arrow(arr_buffer: ArrayBuffer, name: String) -> Result<(), QueryError> {
+    let bytes = Bytes::from(Uint8Array::new(&arr_buffer).to_vec());
+    let reader = ParquetRecordBatchReaderBuilder::try_new(bytes)?.build()?;
+    let location = Path::from(format!("{name}.arrow"));
+    let schema = reader.schema();
+    write_arrow_file(&location, &schema, stream::iter(reader)).await?;
+    Ok(())
+}
+
+/// Writes `batches` to `location` as a Parquet file. The bytes of every completed row
+/// group go to the file before the next batch is read.
+pub(crate) async fn write_parquet_file(
+    location: &Path,
+    mut batches: SendableRecordBatchStream,
+    properties: WriterProperties,
+) -> Result<(), QueryError> {
+    let mut upload = opfs_store().put_multipart(location).await?;
+    let result = async {
+        let mut parquet = ArrowWriter::try_new(Vec::new(), batches.schema(), Some(properties))?;
+        while let Some(batch) = batches.next().await {
+            parquet.write(&batch?)?;
+            // the writer keeps its own offsets, so taking its buffer leaves the file intact
+            put_part(upload.as_mut(), std::mem::take(parquet.inner_mut())).await?;
+        }
+        parquet.finish()?;
+        put_part(upload.as_mut(), std::mem::take(parquet.inner_mut())).await?;
+        Ok::<(), QueryError>(())
+    }
+    .await;
+    finish(upload, result).await
+}
+
+async fn put_part(upload: &mut dyn MultipartUpload, bytes: Vec<u8>) -> Result<(), QueryError> {
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    upload.put_part(PutPayload::from(bytes)).await?;
+    Ok(())
+}
+
+/// Completes the upload if everything was written, otherwise aborts it
+async fn finish(
+    mut upload: Box<dyn MultipartUpload>,
+    result: Result<(), QueryError>,
+) -> Result<(), QueryError> {
+    match result {
+        Ok(()) => {
+            upload.complete().await?;
+            Ok(())
+        }
+        Err(error) => {
+            let _ = upload.abort().await;
+            Err(error)
+        }
+    }
+}
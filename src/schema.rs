@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use datafusion::arrow::datatypes::{DataType, Field, Schema};
+use serde::Serialize;
+
+/// Schema of a table as handed to JS by `get_table_schema`
+#[derive(Serialize)]
+pub(crate) struct TableSchema {
+    name: String,
+    fields: Vec<FieldSchema>,
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FieldSchema {
+    name: String,
+    /// Arrow type as printed by DataFusion, e.g. `Int64` or `Timestamp(Millisecond, None)`
+    data_type: String,
+    nullable: bool,
+    metadata: HashMap<String, String>,
+    /// Fields of structs, the item field of lists and the entries of maps
+    children: Vec<FieldSchema>,
+}
+
+impl TableSchema {
+    pub fn new(name: String, schema: &Schema) -> TableSchema {
+        TableSchema {
+            name,
+            fields: schema
+                .fields()
+                .iter()
+                .map(|f| FieldSchema::new(f))
+                .collect(),
+            metadata: schema.metadata().clone(),
+        }
+    }
+}
+
+impl FieldSchema {
+    fn new(field: &Field) -> FieldSchema {
+        let children = match field.data_type() {
+            DataType::Struct(fields) => fields.iter().map(|f| FieldSchema::new(f)).collect(),
+            DataType::List(item)
+            | DataType::LargeList(item)
+            | DataType::ListView(item)
+            | DataType::LargeListView(item)
+            | DataType::FixedSizeList(item, _)
+            | DataType::Map(item, _) => vec![FieldSchema::new(item)],
+            DataType::Union(fields, _) => fields.iter().map(|(_, f)| FieldSchema::new(f)).collect(),
+            _ => Vec::new(),
+        };
+        FieldSchema {
+            name: field.name().clone(),
+            data_type: field.data_type().to_string(),
+            nullable: field.is_nullable(),
+            metadata: field.metadata().clone(),
+            children,
+        }
+    }
+}
+
+/// Entry of the CodeMirror autocompletion, `{"label": "column", "type": "property"}`
+#[derive(Serialize)]
+pub(crate) struct Completion<'a> {
+    label: &'a str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+/// `{"<table>": [completion, ...]}` with one completion per column, as a JSON string
+pub(crate) fn completions(table_name: &str, schema: &Schema) -> Result<String, serde_json::Error> {
+    let columns: Vec<Completion> = schema
+        .fields()
+        .iter()
+        .map(|field| Completion {
+            label: field.name(),
+            kind: "property",
+        })
+        .collect();
+    let mut completions = HashMap::new();
+    completions.insert(table_name, columns);
+    serde_json::to_string(&completions)
+}
@@ -0,0 +1,671 @@
+use datafusion::arrow::array::RecordBatchWriter;
+use datafusion::arrow::datatypes::{Schema, SchemaRef};
+use datafusion::arrow::error::ArrowError;
+use datafusion::arrow::ipc::writer::IpcWriteOptions;
+use datafusion::arrow::ipc::writer::StreamWriter;
+use datafusion::arrow::ipc::MetadataVersion;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::common::{ParamValues, ScalarValue};
+use datafusion::execution::options::ArrowReadOptions;
+use datafusion::logical_expr::LogicalPlan;
+use datafusion::physical_plan::{collect, execute_stream};
+use datafusion::prelude::*;
+use datafusion::sql::TableReference;
+use futures::lock::Mutex;
+use futures::{Stream, StreamExt, TryStreamExt};
+use js_sys::{Array, Date, Object, Uint8Array};
+use object_store::path::Path;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::pin::Pin;
+use std::sync::Arc;
+use wasm_bindgen::prelude::*;
+use wasm_streams::ReadableStream;
+use web_sys::console;
+
+use crate::catalog;
+use crate::control::{Cancellation, QueryControl};
+use crate::csv_import::{write_arrow_file, CsvConfig};
+use crate::error::{ErrorKind, QueryError};
+use crate::explain::{ExplainOptions, Explanation, LogicalNode, MeasuredExec, PhysicalNode};
+use crate::manifest::{CsvTableOptions, Manifest, ManifestTable, TableFormat};
+use crate::parquet_io::{write_parquet_file, PersistFormat, PersistOptions};
+use crate::schema::{completions, TableSchema};
+use crate::{_opfs_url, opfs_store};
+
+/// A query session with its own tables and settings.
+/// All sessions read and write the same OPFS files, only the catalog is separate.
+#[wasm_bindgen]
+pub struct QueryEngine {
+    ctx: SessionContext,
+    /// Where the tables registered through this session are persisted, if anywhere
+    manifest: Option<Path>,
+    /// The manifest as written last, `None` until its tables are registered
+    tables: Mutex<Option<Manifest>>,
+}
+
+impl Default for QueryEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl QueryEngine {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> QueryEngine {
+        let mut config = SessionConfig::new();
+        // keep the positions of SQL nodes so plan errors can point at the statement
+        config.options_mut().sql_parser.collect_spans = true;
+        let ctx = SessionContext::new_with_config(config);
+        ctx.register_object_store(_opfs_url(), opfs_store());
+        QueryEngine {
+            ctx,
+            manifest: None,
+            tables: Mutex::new(Some(Manifest::default())),
+        }
+    }
+
+    /// Session whose registered tables are kept in OPFS under the name `workspace`,
+    /// the tables registered in an earlier page load are available right away
+    pub async fn open(workspace: String) -> Result<QueryEngine, QueryError> {
+        let engine = QueryEngine::with_manifest(Manifest::location(&workspace));
+        engine.ctx().await?;
+        Ok(engine)
+    }
+
+    /// Change a setting of this session only, e.g. `datafusion.execution.batch_size`
+    pub fn set_option(&self, key: String, value: String) -> Result<(), QueryError> {
+        let state = self.ctx.state_ref();
+        let mut state = state.write();
+        state.config_mut().options_mut().set(&key, &value)?;
+        Ok(())
+    }
+
+    pub async fn unregister_table(&self, table_name: String) -> Result<(), QueryError> {
+        let table_ref = TableReference::from(table_name.clone());
+        self.ctx().await?.deregister_table(table_ref)?;
+        self.update_manifest(|manifest| manifest.tables.remove(&table_name).is_some())
+            .await
+    }
+
+    pub async fn register_table(
+        &self,
+        file_digest: String,
+        table_name: String,
+    ) -> Result<(), QueryError> {
+        let ctx = self.ctx().await?;
+        let table_ref = TableReference::from(table_name.clone());
+        if !ctx.table_exist(table_ref)? {
+            let table = ManifestTable {
+                digest: file_digest,
+                format: TableFormat::Arrow,
+                csv: CsvTableOptions::default(),
+                schema: None,
+            };
+            self.register(&table_name, &table).await?;
+            self.persist(&table_name, table).await?;
+        }
+        Ok(())
+    }
+
+    /// Name, type, nullability, metadata and nested children of every column, plus the
+    /// metadata of the table
+    pub async fn get_table_schema(&self, table_name: String) -> Result<JsValue, QueryError> {
+        let table_ref = TableReference::from(table_name.clone());
+        let table = self.ctx().await?.table(table_ref).await?;
+        let schema = Schema::from(table.schema());
+        to_js(&TableSchema::new(table_name, &schema))
+    }
+
+    /// The columns of the table in the shape of the CodeMirror autocompletion,
+    /// `{"<table>": [{"label": "<column>", "type": "property"}, ...]}` as JSON string
+    pub async fn get_table_completions(&self, table_name: String) -> Result<JsValue, QueryError> {
+        let table_ref = TableReference::from(table_name.clone());
+        let table = self.ctx().await?.table(table_ref).await?;
+        let schema = Schema::from(table.schema());
+        let json_str = completions(&table_name, &schema)
+            .map_err(|e| QueryError::new(ErrorKind::Execution, e.to_string()))?;
+        Ok(JsValue::from(json_str))
+    }
+
+    /// Registers the newline delimited JSON file `<file_digest>.json` as a table
+    pub async fn register_json(
+        &self,
+        file_digest: String,
+        table_name: String,
+    ) -> Result<(), QueryError> {
+        let ctx = self.ctx().await?;
+        let table_ref = TableReference::from(table_name.clone());
+        if !ctx.table_exist(table_ref)? {
+            let table = ManifestTable {
+                digest: file_digest,
+                format: TableFormat::Json,
+                csv: CsvTableOptions::default(),
+                schema: None,
+            };
+            self.register(&table_name, &table).await?;
+            self.persist(&table_name, table).await?;
+        }
+        Ok(())
+    }
+
+    /// Names of all catalogs
+    pub fn list_catalogs(&self) -> Vec<String> {
+        let mut names = self.ctx.catalog_names();
+        names.sort();
+        names
+    }
+
+    /// `[{catalog, name}]` for the schemas of `catalog`, or of all catalogs
+    pub fn list_schemas(&self, catalog: Option<String>) -> Result<JsValue, QueryError> {
+        to_js(&catalog::schemas(&self.ctx, catalog.as_deref()))
+    }
+
+    /// `[{catalog, schema, name, tableType, paths}]` for the tables of `catalog` and `schema`,
+    /// leaving out either lists them across all catalogs or schemas
+    pub async fn list_tables(
+        &self,
+        catalog: Option<String>,
+        schema: Option<String>,
+    ) -> Result<JsValue, QueryError> {
+        let tables =
+            catalog::tables(self.ctx().await?, catalog.as_deref(), schema.as_deref()).await?;
+        to_js(&tables)
+    }
+
+    /// `[{name, kind, aliases, signatures}]` for every scalar, aggregate and window function
+    pub fn list_functions(&self) -> Result<JsValue, QueryError> {
+        to_js(&catalog::functions(&self.ctx.state()))
+    }
+
+    /// Registers `<file_digest>.csv` as a table, read with the `delimiter`, `quote`,
+    /// `escape`, `comment` and `has_header` of `csv_config`, the defaults if it is missing
+    pub async fn register_csv(
+        &self,
+        file_digest: String,
+        table_name: String,
+        csv_config: Option<JsValue>,
+    ) -> Result<(), QueryError> {
+        let cfg: CsvConfig = match csv_config.filter(|cfg| !cfg.is_undefined() && !cfg.is_null()) {
+            Some(csv_config) => serde_wasm_bindgen::from_value(csv_config)?,
+            None => CsvConfig::default(),
+        };
+        let ctx = self.ctx().await?;
+        let table_ref = TableReference::from(table_name.clone());
+        if !ctx.table_exist(table_ref)? {
+            let table = ManifestTable {
+                digest: file_digest,
+                format: TableFormat::Csv,
+                csv: CsvTableOptions::from_config(&cfg),
+                schema: None,
+            };
+            self.register(&table_name, &table).await?;
+            self.persist(&table_name, table).await?;
+        }
+        Ok(())
+    }
+
+    /// Registers the Parquet file `<file_digest>.parquet` as a table
+    pub async fn register_parquet(
+        &self,
+        file_digest: String,
+        table_name: String,
+    ) -> Result<(), QueryError> {
+        let ctx = self.ctx().await?;
+        let table_ref = TableReference::from(table_name.clone());
+        if !ctx.table_exist(table_ref)? {
+            let table = ManifestTable {
+                digest: file_digest,
+                format: TableFormat::Parquet,
+                csv: CsvTableOptions::default(),
+                schema: None,
+            };
+            self.register(&table_name, &table).await?;
+            self.persist(&table_name, table).await?;
+        }
+        Ok(())
+    }
+
+    /// `options` may hold an `AbortSignal` as `signal` and a `timeout` in milliseconds,
+    /// either one stops the query and rejects with a `cancelled` error
+    /// `onProgress` is called with the rows scanned, bytes read, batches produced and
+    /// the elapsed milliseconds while the query runs.
+    pub async fn run_sql(
+        &self,
+        sql_query: String,
+        options: Option<Object>,
+    ) -> Result<JsValue, QueryError> {
+        let control = QueryControl::from_options(options)?;
+        let cancellation = control.watch()?;
+        // create a plan to run a SQL query
+        let df = self.ctx().await?.sql(sql_query.as_str()).await?;
+        collect_ipc(df, &control, cancellation).await
+    }
+
+    /// Parses and plans `sql_query` once, `$1` or `$name` placeholders are filled in
+    /// by `PreparedStatement.execute`
+    pub async fn prepare(&self, sql_query: String) -> Result<PreparedStatement, QueryError> {
+        let df = self.ctx().await?.sql(sql_query.as_str()).await?;
+        Ok(PreparedStatement {
+            ctx: self.ctx.clone(),
+            plan: df.into_unoptimized_plan(),
+        })
+    }
+
+    /// Runs the query and returns a `ReadableStream` of `Uint8Array` chunks. The first chunk is
+    /// the IPC schema message, every following one holds one record batch, and the last one
+    /// the end of stream marker, so the concatenated chunks equal the output of `run_sql`.
+    /// Batches are only computed when the reader pulls, a slow consumer holds back the query.
+    /// Takes the same `options` as `run_sql`, cancelling errors the stream.
+    pub async fn run_sql_stream(
+        &self,
+        sql_query: String,
+        options: Option<Object>,
+    ) -> Result<web_sys::ReadableStream, QueryError> {
+        let control = QueryControl::from_options(options)?;
+        let cancellation = control.watch()?;
+        let df = self.ctx().await?.sql(sql_query.as_str()).await?;
+        let (schema, batches) = execute(df, &control).await?;
+        let chunks = cancellation.guard(Box::pin(ipc_chunks(&schema, batches)?));
+        let chunks = chunks.map(|chunk| match chunk {
+            Ok(bytes) => Ok(JsValue::from(Uint8Array::from(&bytes[..]))),
+            Err(error) => Err(JsValue::from(error)),
+        });
+        Ok(ReadableStream::from_stream(chunks).into_raw())
+    }
+
+    /// Returns the logical and physical plan of `sql_query` as trees, `options` is
+    /// `{ analyze?: boolean, verbose?: boolean }`. With `analyze` the query runs and
+    /// the physical nodes carry their actual row counts, compute times and output bytes.
+    pub async fn explain_sql(
+        &self,
+        sql_query: String,
+        options: JsValue,
+    ) -> Result<JsValue, QueryError> {
+        let options: ExplainOptions = if options.is_undefined() || options.is_null() {
+            ExplainOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options)?
+        };
+        let df = self.ctx().await?.sql(sql_query.as_str()).await?;
+        let initial_logical = options
+            .verbose
+            .then(|| LogicalNode::new(df.logical_plan(), true));
+        let (state, plan) = df.into_parts();
+        let logical = state.optimize(&plan)?;
+        let physical = state.create_physical_plan(&logical).await?;
+
+        let (physical, output_bytes) = if options.analyze {
+            let physical = MeasuredExec::wrap(physical)?;
+            let results = collect(Arc::clone(&physical), self.ctx.task_ctx()).await?;
+            let output_bytes = results.iter().map(RecordBatch::get_array_memory_size).sum();
+            (physical, Some(output_bytes))
+        } else {
+            (physical, None)
+        };
+
+        let explanation = Explanation {
+            initial_logical,
+            logical: LogicalNode::new(&logical, options.verbose),
+            physical: PhysicalNode::new(&physical, options.verbose),
+            output_bytes,
+        };
+        to_js(&explanation)
+    }
+
+    /// Writes the result of the query to `<file_name>.arrow`, or to `<file_name>.parquet`
+    /// with `options` `{ format: "parquet", compression?: string, row_group_size?: number }`.
+    /// Both are written while the query runs, Arrow batch by batch and Parquet row group
+    /// by row group.
+    pub async fn persist_sql(
+        &self,
+        sql_query: String,
+        file_name: String,
+        options: JsValue,
+    ) -> Result<(), QueryError> {
+        let options: PersistOptions = if options.is_undefined() || options.is_null() {
+            PersistOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options)?
+        };
+        // create a plan to run a SQL query
+        let df = self.ctx().await?.sql(sql_query.as_str()).await?;
+        if options.format == PersistFormat::Parquet {
+            let properties = options.writer_properties()?;
+            let location = Path::from(format!("{file_name}.parquet"));
+            let batches = df.execute_stream().await?;
+            return write_parquet_file(&location, batches, properties).await;
+        }
+        let location = Path::from(format!("{file_name}.arrow"));
+        let batches = df.execute_stream().await?;
+        let schema = batches.schema();
+        write_arrow_file(&location, &schema, batches.map_err(ArrowError::from)).await?;
+        Ok(())
+    }
+}
+
+impl QueryEngine {
+    pub(crate) fn with_manifest(location: Path) -> QueryEngine {
+        QueryEngine {
+            manifest: Some(location),
+            tables: Mutex::new(None),
+            ..QueryEngine::new()
+        }
+    }
+
+    /// The session context, once the tables of the manifest are registered
+    async fn ctx(&self) -> Result<&SessionContext, QueryError> {
+        let location = match &self.manifest {
+            Some(location) => location,
+            None => return Ok(&self.ctx),
+        };
+        let mut tables = self.tables.lock().await;
+        if tables.is_none() {
+            let manifest = match Manifest::load(location).await {
+                Ok(manifest) => manifest,
+                Err(error) => {
+                    // a broken manifest shouldn't lock the user out of the session
+                    console::warn_1(
+                        &format!(
+                            "Could not read the catalog, starting with an empty one: {}",
+                            error.to_js_string()
+                        )
+                        .into(),
+                    );
+                    Manifest::set_aside(location).await;
+                    Manifest::default()
+                }
+            };
+            for (name, table) in &manifest.tables {
+                if let Err(error) = self.register(name, table).await {
+                    // the file may be gone, that shouldn't cost the user the rest of the workspace
+                    console::warn_1(
+                        &format!("Could not restore table {name}: {}", error.to_js_string()).into(),
+                    );
+                }
+            }
+            *tables = Some(manifest);
+        }
+        Ok(&self.ctx)
+    }
+
+    async fn register(&self, table_name: &str, table: &ManifestTable) -> Result<(), QueryError> {
+        let register_path = format!("opfs:///{}.{}", table.digest, table.format.extension());
+        match table.format {
+            TableFormat::Arrow => {
+                let options = ArrowReadOptions {
+                    schema: table.schema.as_ref(),
+                    ..ArrowReadOptions::default()
+                };
+                // register as table
+                self.ctx
+                    .register_arrow(table_name, register_path.as_str(), options)
+                    .await?;
+            }
+            TableFormat::Csv => {
+                let csv = &table.csv;
+                let mut options = CsvReadOptions::new()
+                    .has_header(csv.has_header)
+                    .delimiter(ascii(csv.delimiter, "delimiter")?)
+                    .quote(ascii(csv.quote, "quote")?);
+                if let Some(escape) = csv.escape {
+                    options = options.escape(ascii(escape, "escape")?);
+                }
+                if let Some(comment) = csv.comment {
+                    options = options.comment(ascii(comment, "comment")?);
+                }
+                if let Some(schema) = &table.schema {
+                    options = options.schema(schema);
+                }
+                // register CSV as table
+                self.ctx
+                    .register_csv(table_name, register_path.as_str(), options)
+                    .await?;
+            }
+            TableFormat::Json => {
+                let mut options = NdJsonReadOptions::default();
+                if let Some(schema) = &table.schema {
+                    options = options.schema(schema);
+                }
+                self.ctx
+                    .register_json(table_name, register_path.as_str(), options)
+                    .await?;
+            }
+            TableFormat::Parquet => {
+                let mut options = ParquetReadOptions::default();
+                if let Some(schema) = &table.schema {
+                    options = options.schema(schema);
+                }
+                self.ctx
+                    .register_parquet(table_name, register_path.as_str(), options)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the registered `table_name` to the manifest, together with its schema
+    async fn persist(&self, table_name: &str, mut table: ManifestTable) -> Result<(), QueryError> {
+        if self.manifest.is_none() {
+            return Ok(());
+        }
+        let provider = self.ctx.table_provider(table_name).await?;
+        table.schema = Some(provider.schema().as_ref().clone());
+        self.update_manifest(|manifest| {
+            manifest
+                .tables
+                .insert(table_name.to_string(), table.clone());
+            true
+        })
+        .await
+    }
+
+    /// Applies `change` to the manifest and, if it returns true, writes the manifest.
+    /// Holding the lock until it is written keeps concurrent changes from getting lost.
+    /// If another session wrote the manifest in the meantime, its version is loaded and
+    /// `change` applied again, so the tables that session added are kept.
+    async fn update_manifest(
+        &self,
+        change: impl Fn(&mut Manifest) -> bool,
+    ) -> Result<(), QueryError> {
+        let location = match &self.manifest {
+            Some(location) => location,
+            None => return Ok(()),
+        };
+        let mut tables = self.tables.lock().await;
+        let manifest = tables.get_or_insert_with(Manifest::default);
+        while change(manifest) {
+            if manifest.save(location).await? {
+                break;
+            }
+            *manifest = Manifest::load(location).await?;
+        }
+        Ok(())
+    }
+}
+
+/// `c` as the byte the CSV reader expects
+fn ascii(c: char, option: &str) -> Result<u8, QueryError> {
+    u8::try_from(c).map_err(|_| QueryError::new(ErrorKind::Parse, format!("Invalid {option} {c}")))
+}
+
+/// A planned query with placeholders, bound to the session it was prepared in
+#[wasm_bindgen]
+pub struct PreparedStatement {
+    ctx: SessionContext,
+    plan: LogicalPlan,
+}
+
+#[wasm_bindgen]
+impl PreparedStatement {
+    /// Runs the statement like `run_sql`. `params` is an array for `$1`, `$2`, ... or an
+    /// object for `$name` placeholders, values can be numbers, strings, booleans, BigInts,
+    /// Dates or null.
+    pub async fn execute(
+        &self,
+        params: JsValue,
+        options: Option<Object>,
+    ) -> Result<JsValue, QueryError> {
+        let control = QueryControl::from_options(options)?;
+        let cancellation = control.watch()?;
+        let params = param_values(&params)?;
+        let df = DataFrame::new(self.ctx.state(), self.plan.clone()).with_param_values(params)?;
+        collect_ipc(df, &control, cancellation).await
+    }
+}
+
+/// Plain JS objects and arrays, maps become objects as well
+pub(crate) fn to_js<T: Serialize>(value: &T) -> Result<JsValue, QueryError> {
+    value
+        .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+        .map_err(|e| QueryError::new(ErrorKind::Execution, e.to_string()))
+}
+
+/// Starts executing `df`, with progress reports if `control` asks for them
+async fn execute(
+    df: DataFrame,
+    control: &QueryControl,
+) -> Result<(SchemaRef, RecordBatches), QueryError> {
+    let task_ctx = df.task_ctx();
+    let plan = df.create_physical_plan().await?;
+    let schema = plan.schema();
+    let reporter = control.progress(Arc::clone(&plan));
+    let task_ctx = match &reporter {
+        Some(reporter) => reporter.count_reads(task_ctx),
+        None => task_ctx,
+    };
+    let batches = execute_stream(plan, Arc::new(task_ctx))?.map(|batch| Ok(batch?));
+    let batches: RecordBatches = match reporter {
+        Some(reporter) => Box::pin(reporter.observe(batches)?),
+        None => Box::pin(batches),
+    };
+    Ok((schema, batches))
+}
+
+/// Runs `df` to the end and serializes the result as an Arrow IPC stream
+async fn collect_ipc(
+    df: DataFrame,
+    control: &QueryControl,
+    cancellation: Cancellation,
+) -> Result<JsValue, QueryError> {
+    let (schema, batches) = execute(df, control).await?;
+    // execute the plan and collect the results as Vec<RecordBatch>
+    let results: Vec<RecordBatch> = cancellation.guard(batches).try_collect().await?;
+
+    // serialize to in memory vector
+    let mut output: Vec<u8> = Vec::new();
+
+    let options = IpcWriteOptions::try_new(8, false, MetadataVersion::V5)?;
+    let mut writer = StreamWriter::try_new_with_options(&mut output, &schema, options)?;
+    for batch in results {
+        writer.write(&batch)?;
+    }
+    writer.close()?;
+
+    let js_arr = Uint8Array::from(&output[..]);
+    Ok(JsValue::from(&js_arr))
+}
+
+/// Arrays are positional parameters, objects named ones
+fn param_values(params: &JsValue) -> Result<ParamValues, QueryError> {
+    if params.is_undefined() || params.is_null() {
+        return Ok(ParamValues::List(Vec::new()));
+    }
+    if Array::is_array(params) {
+        let values = Array::from(params)
+            .iter()
+            .map(|value| scalar_value(&value))
+            .collect::<Result<_, _>>()?;
+        return Ok(ParamValues::List(values));
+    }
+    if !params.is_object() {
+        return Err(QueryError::new(
+            ErrorKind::Parse,
+            "Parameters must be an array or an object",
+        ));
+    }
+    let mut values = HashMap::new();
+    for entry in Object::entries(params.unchecked_ref::<Object>()).iter() {
+        let entry = Array::from(&entry);
+        let name = entry.get(0).as_string().unwrap_or_default();
+        values.insert(name, scalar_value(&entry.get(1))?);
+    }
+    Ok(ParamValues::Map(values))
+}
+
+fn scalar_value(value: &JsValue) -> Result<ScalarValue, QueryError> {
+    if value.is_null() || value.is_undefined() {
+        Ok(ScalarValue::Null)
+    } else if let Some(value) = value.as_bool() {
+        Ok(ScalarValue::Boolean(Some(value)))
+    } else if let Some(value) = value.as_string() {
+        Ok(ScalarValue::Utf8(Some(value)))
+    } else if let Some(value) = value.as_f64() {
+        // whole numbers are integers as long as a double represents them exactly
+        if value.fract() == 0.0 && value.abs() <= MAX_SAFE_INTEGER {
+            Ok(ScalarValue::Int64(Some(value as i64)))
+        } else {
+            Ok(ScalarValue::Float64(Some(value)))
+        }
+    } else if value.is_bigint() {
+        let value = i64::try_from(value.clone()).map_err(|_| {
+            QueryError::new(
+                ErrorKind::Parse,
+                "BigInt parameter does not fit into 64 bits",
+            )
+        })?;
+        Ok(ScalarValue::Int64(Some(value)))
+    } else if let Some(date) = value.dyn_ref::<Date>() {
+        let millis = date.get_time();
+        if millis.is_nan() {
+            return Err(QueryError::new(ErrorKind::Parse, "Invalid Date parameter"));
+        }
+        Ok(ScalarValue::TimestampMillisecond(Some(millis as i64), None))
+    } else {
+        Err(QueryError::new(
+            ErrorKind::Parse,
+            format!("Unsupported parameter {value:?}, expected a number, string, boolean, BigInt, Date or null"),
+        ))
+    }
+}
+
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_991.0;
+
+type RecordBatches = Pin<Box<dyn Stream<Item = Result<RecordBatch, QueryError>>>>;
+
+/// Encodes `batches` as an Arrow IPC stream, one chunk per message
+fn ipc_chunks(
+    schema: &Schema,
+    batches: RecordBatches,
+) -> Result<impl Stream<Item = Result<Vec<u8>, QueryError>>, QueryError> {
+    let options = IpcWriteOptions::try_new(8, false, MetadataVersion::V5)?;
+    // the writer puts the schema message into its buffer right away
+    let writer = StreamWriter::try_new_with_options(Vec::new(), schema, options)?;
+    let stream = futures::stream::unfold(Some((batches, writer, true)), |state| async move {
+        let (mut batches, mut writer, first) = state?;
+        if first {
+            let schema = std::mem::take(writer.get_mut());
+            return Some((Ok(schema), Some((batches, writer, false))));
+        }
+        let written = match batches.next().await {
+            Some(Ok(batch)) => writer.write(&batch).map(|_| true),
+            Some(Err(error)) => return Some((Err(error), None)),
+            None => writer.finish().map(|_| false),
+        };
+        match written {
+            Ok(more) => {
+                let chunk = std::mem::take(writer.get_mut());
+                let state = if more {
+                    Some((batches, writer, false))
+                } else {
+                    None
+                };
+                Some((Ok(chunk), state))
+            }
+            Err(error) => Some((Err(error.into()), None)),
+        }
+    });
+    Ok(stream)
+}
@@ -1,226 +1,750 @@
-use std::{io::Cursor, sync::Arc};
-
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
-use datafusion::arrow::array::RecordBatchWriter;
-use datafusion::arrow::{
-    csv::{reader::Format, ReaderBuilder},
-    error::ArrowError,
-    ipc::{
-        writer::{FileWriter, IpcWriteOptions},
-        MetadataVersion,
+use futures::{
+    channel::{
+        mpsc::{self, UnboundedReceiver},
+        oneshot::Sender,
     },
+    SinkExt, StreamExt,
+};
+use js_sys::{ArrayBuffer, Function, IteratorNext, Promise, Reflect, Uint8Array};
+use object_store::{
+    path::{Path, PathPart},
+    GetRange, PutPayload,
 };
-use futures::{channel::oneshot::Sender, FutureExt};
-use js_sys::{try_iter, ArrayBuffer, Promise, Uint8Array};
-use object_store::{path::Path, ObjectMeta};
-use regex::Regex;
-use serde::Deserialize;
-use std::io::Seek;
 use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-    window, File, FileSystemDirectoryHandle, FileSystemFileHandle, FileSystemGetDirectoryOptions,
-    FileSystemGetFileOptions, FileSystemWritableFileStream, Window,
+    console, window, Blob, File, FileSystemDirectoryHandle, FileSystemFileHandle,
+    FileSystemGetDirectoryOptions, FileSystemGetFileOptions, FileSystemHandle,
+    FileSystemWritableFileStream, ReadableStreamDefaultReader, ReadableStreamReadResult, Window,
 };
 
+use crate::manifest::CATALOG_FOLDER;
+use crate::opfs_store::OpfsError;
+
+/// Upper bound for the size of the chunks sent by [`stream_file_data`]
+pub const STREAM_CHUNK_SIZE: u32 = 1024 * 1024;
+
 #[derive(Debug)]
 pub struct FileResponse {
-    pub bytes: Option<Bytes>,
+    /// One entry per range requested from [`get_file_data`]
+    pub bytes: Vec<Bytes>,
     pub name: String,
     pub last_modified: DateTime<Utc>,
     pub size: u64,
 }
 
-#[derive(Deserialize)]
-pub struct CsvConfig {
-    pub delimiter: String,
-    pub quote: String,
-    pub comment: String,
-    pub escape: String,
-    pub null_regex: String,
-    pub truncated: bool,
+impl FileResponse {
+    fn from_file(file: &File, bytes: Vec<Bytes>) -> FileResponse {
+        let milliseconds_since: i64 = file.last_modified() as i64;
+        FileResponse {
+            bytes,
+            name: file.name(),
+            last_modified: DateTime::from_timestamp_millis(milliseconds_since).unwrap_or_default(),
+            size: file.size() as u64,
+        }
+    }
+}
+
+/// Requests handled in order by the writer task spawned in [`open_file_writer`]
+#[derive(Debug)]
+pub(crate) enum WriterRequest {
+    /// Append the payload and acknowledge once it is written to the stream
+    Part(PutPayload, Sender<Result<(), OpfsError>>),
+    /// Close the stream, committing its content to the file
+    Complete(Sender<Result<FileResponse, OpfsError>>),
+    /// Abort the stream, discarding everything written so far
+    Abort(Sender<Result<(), OpfsError>>),
+}
+
+#[derive(Debug, Default)]
+pub struct FolderResponse {
+    pub files: Vec<(Path, FileResponse)>,
+    pub folders: Vec<Path>,
+}
+
+/// Attaches the path an OPFS operation was working on to a JS exception
+trait JsResultExt<T> {
+    fn for_path(self, location: &Path) -> Result<T, OpfsError>;
+}
+
+impl<T> JsResultExt<T> for Result<T, JsValue> {
+    fn for_path(self, location: &Path) -> Result<T, OpfsError> {
+        self.map_err(|error| OpfsError::from_js(location, error))
+    }
 }
 
-pub async fn get_file_folder(window: &Window) -> FileSystemDirectoryHandle {
+/// Opens the data folder of the origin private file system, creating it if needed
+pub async fn try_get_file_folder(window: &Window) -> Result<FileSystemDirectoryHandle, JsValue> {
     let navigator = window.navigator();
     let storage = navigator.storage();
-    let root = get_from_promise::<FileSystemDirectoryHandle>(storage.get_directory()).await;
+    let root = try_get_from_promise::<FileSystemDirectoryHandle>(storage.get_directory()).await?;
     let options = &FileSystemGetDirectoryOptions::new();
     options.set_create(true);
-    return get_from_promise::<FileSystemDirectoryHandle>(
+    try_get_from_promise::<FileSystemDirectoryHandle>(
         root.get_directory_handle_with_options("data", options),
     )
-    .await;
+    .await
+}
+
+fn get_window() -> Result<Window, JsValue> {
+    window().ok_or_else(|| js_sys::Error::new("OPFS access requires a window").into())
+}
+
+async fn get_data_folder() -> Result<FileSystemDirectoryHandle, JsValue> {
+    // moving Window as ref from the static async context to prevent loss of context
+    let window: Window = get_window()?;
+    try_get_file_folder(&window).await
+}
+
+/// Resolves the folder holding `location`, mapping every path segment but the last one
+/// to a nested folder below the data folder. Missing folders are created if `create` is
+/// set. Returns the folder together with the file name.
+pub async fn get_parent_folder(
+    window: &Window,
+    location: &Path,
+    create: bool,
+) -> Result<(FileSystemDirectoryHandle, String), JsValue> {
+    let mut folder = try_get_file_folder(window).await?;
+    let mut parts: Vec<_> = location.parts().collect();
+    let name = parts
+        .pop()
+        .map(|part| part.as_ref().to_string())
+        .unwrap_or_default();
+    let options = &FileSystemGetDirectoryOptions::new();
+    options.set_create(create);
+    for part in parts {
+        folder = try_get_from_promise::<FileSystemDirectoryHandle>(
+            folder.get_directory_handle_with_options(part.as_ref(), options),
+        )
+        .await?;
+    }
+    Ok((folder, name))
+}
+
+/// Resolves the folder at `location`, see [`get_parent_folder`]
+async fn get_folder(
+    window: &Window,
+    location: &Path,
+) -> Result<FileSystemDirectoryHandle, JsValue> {
+    let mut folder = try_get_file_folder(window).await?;
+    for part in location.parts() {
+        folder = try_get_from_promise::<FileSystemDirectoryHandle>(
+            folder.get_directory_handle(part.as_ref()),
+        )
+        .await?;
+    }
+    Ok(folder)
+}
+
+async fn get_file_handle(
+    window: &Window,
+    location: &Path,
+) -> Result<FileSystemFileHandle, JsValue> {
+    let (folder, name) = get_parent_folder(window, location, false).await?;
+    try_get_from_promise::<FileSystemFileHandle>(folder.get_file_handle(name.as_str())).await
+}
+
+/// Collects the handles of all files and folders directly inside `folder`
+async fn read_folder(folder: &FileSystemDirectoryHandle) -> Result<Vec<FileSystemHandle>, JsValue> {
+    let entries = folder.values();
+    let mut handles = Vec::new();
+    loop {
+        let next = JsFuture::from(entries.next()?)
+            .await?
+            .unchecked_into::<IteratorNext>();
+        if next.done() {
+            return Ok(handles);
+        }
+        handles.push(next.value().unchecked_into::<FileSystemHandle>());
+    }
+}
+
+/// The path segment of the entry `name`. Names written through the store are valid
+/// segments already, anything else is skipped with a warning: its encoded form would not
+/// resolve to the entry again.
+fn path_part(name: &str) -> Option<PathPart<'_>> {
+    match PathPart::parse(name) {
+        Ok(part) => Some(part),
+        Err(e) => {
+            console::warn_1(&format!("Skipping OPFS entry: {e}").into());
+            None
+        }
+    }
+}
+
+/// Whether the entry `name` of the folder at `folder` belongs to the data, the catalog
+/// manifests and unfinished uploads do not
+fn is_data(folder: &Path, name: &str) -> bool {
+    !(folder.as_ref().is_empty() && (name == CATALOG_FOLDER || name == UPLOAD_FOLDER))
 }
 
-pub async fn get_from_promise<T: JsCast>(promise: Promise) -> T {
-    return JsFuture::from(promise)
-        .map(|result| match result {
-            Ok(value) => {
-                assert!(value.has_type::<T>());
-                Ok(value.unchecked_into::<T>())
+/// Awaits `promise` and casts its value to `T`, a rejected promise or a value of the
+/// wrong type is an error
+pub async fn try_get_from_promise<T: JsCast>(promise: Promise) -> Result<T, JsValue> {
+    JsFuture::from(promise).await?.dyn_into::<T>().map_err(|_| {
+        js_sys::TypeError::new("Promise resolved to a value of an unexpected type").into()
+    })
+}
+
+/// Bytes of a CSV import, read in chunks so the source never has to be copied
+/// into WASM memory as a whole
+pub enum CsvSource {
+    Buffer(Uint8Array),
+    Blob(Blob),
+}
+
+impl CsvSource {
+    pub(crate) fn len(&self) -> u64 {
+        match self {
+            CsvSource::Buffer(buffer) => buffer.length() as u64,
+            CsvSource::Blob(blob) => blob.size() as u64,
+        }
+    }
+
+    pub(crate) async fn read(
+        &self,
+        start: u64,
+        end: u64,
+        location: &Path,
+    ) -> Result<Vec<u8>, OpfsError> {
+        match self {
+            CsvSource::Buffer(buffer) => Ok(buffer.subarray(start as u32, end as u32).to_vec()),
+            CsvSource::Blob(blob) => {
+                let slice = blob
+                    .slice_with_f64_and_f64(start as f64, end as f64)
+                    .for_path(location)?;
+                let buffer = try_get_from_promise::<ArrayBuffer>(slice.array_buffer())
+                    .await
+                    .for_path(location)?;
+                Ok(Uint8Array::new(&buffer).to_vec())
             }
+        }
+    }
+}
+
+/// Creates or truncates the file at `location` and writes `chunks` in order.
+/// The content only becomes visible once the writable stream is closed, so readers
+/// never see a partially written file.
+async fn write_file<'a>(
+    location: &Path,
+    chunks: impl IntoIterator<Item = &'a [u8]>,
+) -> Result<FileResponse, OpfsError> {
+    let options = &FileSystemGetFileOptions::default();
+    options.set_create(true);
+
+    let window: Window = get_window().for_path(location)?;
+    let (import_handle, name) = get_parent_folder(&window, location, true)
+        .await
+        .for_path(location)?;
+    let file_handle = try_get_from_promise::<FileSystemFileHandle>(
+        import_handle.get_file_handle_with_options(name.as_str(), options),
+    )
+    .await
+    .for_path(location)?;
+
+    let write_file_stream =
+        try_get_from_promise::<FileSystemWritableFileStream>(file_handle.create_writable())
+            .await
+            .for_path(location)?;
+
+    for chunk in chunks {
+        let written = match write_file_stream.write_with_u8_array(chunk) {
+            Ok(promise) => JsFuture::from(promise).await,
             Err(e) => Err(e),
-        })
+        };
+        if let Err(e) = written {
+            let _ = JsFuture::from(write_file_stream.abort()).await;
+            return Err(OpfsError::from_js(location, e));
+        }
+    }
+    JsFuture::from(write_file_stream.close())
+        .await
+        .for_path(location)?;
+    let file = try_get_from_promise::<File>(file_handle.get_file())
         .await
-        .unwrap();
+        .for_path(location)?;
+    Ok(FileResponse::from_file(&file, Vec::new()))
 }
 
-pub async fn cp_csv_to_arrow(
-    arr_buffer: ArrayBuffer,
-    name: String,
-    csv_config: JsValue,
-) -> Result<(), ArrowError> {
-    // moving Window as ref from the static async context to prevent loss of context
-    let mut bytes_cursor = Cursor::new(Uint8Array::new(&arr_buffer).to_vec());
-    let cfg: CsvConfig = serde_wasm_bindgen::from_value(csv_config).unwrap();
+pub(crate) fn put_file_data(
+    tx: Sender<Result<FileResponse, OpfsError>>,
+    location: Path,
+    payload: PutPayload,
+) {
+    wasm_bindgen_futures::spawn_local({
+        async move {
+            let resp = write_file(&location, payload.iter().map(|b| b.as_ref())).await;
+            let _ = tx.send(resp);
+        }
+    });
+}
 
-    let delimiter = if cfg.delimiter.len() == 1 {
-        cfg.delimiter.as_bytes()[0]
-    } else {
-        b','
-    };
+/// Folder in the data folder, left out of its listings, holding the files uploads are
+/// written to before they replace their target
+const UPLOAD_FOLDER: &str = ".uploads";
 
-    let mut csv_format = Format::default()
-        .with_header(true)
-        .with_delimiter(delimiter)
-        .with_truncated_rows(cfg.truncated);
+/// The writable stream kept open by [`open_file_writer`]. The content goes to a file in
+/// the [`UPLOAD_FOLDER`], which only replaces the target in [`FileWriterState::complete`].
+pub(crate) struct FileWriterState {
+    folder: FileSystemDirectoryHandle,
+    name: String,
+    upload_folder: FileSystemDirectoryHandle,
+    upload_name: String,
+    upload_handle: FileSystemFileHandle,
+    stream: FileSystemWritableFileStream,
+}
 
-    if cfg.quote.len() == 1 {
-        csv_format = csv_format.with_quote(cfg.quote.as_bytes()[0]);
+impl FileWriterState {
+    pub(crate) async fn open(location: &Path) -> Result<FileWriterState, OpfsError> {
+        // moving Window as ref from the static async context to prevent loss of context
+        let window: Window = get_window().for_path(location)?;
+        let (folder, name) = get_parent_folder(&window, location, true)
+            .await
+            .for_path(location)?;
+        // random, so concurrent uploads to the same target, also from other tabs, do not clash
+        let upload = Path::from(UPLOAD_FOLDER).child(format!(
+            "{:x}-{name}",
+            (js_sys::Math::random() * u32::MAX as f64) as u32
+        ));
+        let (upload_folder, upload_name) = get_parent_folder(&window, &upload, true)
+            .await
+            .for_path(location)?;
+
+        let options = &FileSystemGetFileOptions::default();
+        options.set_create(true);
+        let upload_handle = try_get_from_promise::<FileSystemFileHandle>(
+            upload_folder.get_file_handle_with_options(upload_name.as_str(), options),
+        )
+        .await
+        .for_path(location)?;
+        let stream =
+            try_get_from_promise::<FileSystemWritableFileStream>(upload_handle.create_writable())
+                .await
+                .for_path(location)?;
+        Ok(FileWriterState {
+            folder,
+            name,
+            upload_folder,
+            upload_name,
+            upload_handle,
+            stream,
+        })
     }
-    if cfg.comment.len() == 1 {
-        csv_format = csv_format.with_comment(cfg.comment.as_bytes()[0]);
+
+    pub(crate) async fn write(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+    ) -> Result<(), OpfsError> {
+        for chunk in payload.iter() {
+            JsFuture::from(self.stream.write_with_u8_array(chunk).for_path(location)?)
+                .await
+                .for_path(location)?;
+        }
+        Ok(())
     }
-    if cfg.escape.len() == 1 {
-        csv_format = csv_format.with_escape(cfg.escape.as_bytes()[0]);
+
+    /// Closes the stream and moves the uploaded file to `location`
+    pub(crate) async fn complete(&self, location: &Path) -> Result<FileResponse, OpfsError> {
+        JsFuture::from(self.stream.close())
+            .await
+            .for_path(location)?;
+        if !move_file(&self.upload_handle, &self.folder, &self.name).await {
+            let upload = try_get_from_promise::<File>(self.upload_handle.get_file())
+                .await
+                .for_path(location)?;
+            write_blob(&self.folder, &self.name, &upload, location).await?;
+            JsFuture::from(self.upload_folder.remove_entry(self.upload_name.as_str()))
+                .await
+                .for_path(location)?;
+        }
+        let file_handle = try_get_from_promise::<FileSystemFileHandle>(
+            self.folder.get_file_handle(self.name.as_str()),
+        )
+        .await
+        .for_path(location)?;
+        let file = try_get_from_promise::<File>(file_handle.get_file())
+            .await
+            .for_path(location)?;
+        Ok(FileResponse::from_file(&file, Vec::new()))
     }
-    if cfg.null_regex.len() > 0 && cfg.null_regex.len() <= 32 {
-        csv_format = csv_format.with_null_regex(Regex::new(&cfg.null_regex).unwrap());
+
+    /// Aborts the stream and removes the uploaded file, `location` is left as it was
+    pub(crate) async fn discard(&self, location: &Path) -> Result<(), OpfsError> {
+        JsFuture::from(self.stream.abort())
+            .await
+            .for_path(location)?;
+        JsFuture::from(self.upload_folder.remove_entry(self.upload_name.as_str()))
+            .await
+            .for_path(location)?;
+        Ok(())
     }
+}
 
-    let (schema, _) = csv_format
-        .infer_schema(&mut bytes_cursor, Some(1000))
-        .unwrap();
-    bytes_cursor.rewind().unwrap();
+/// Keeps a writable stream for `location` open and applies the requests received on `rx`.
+/// The parts are written to a file in the [`UPLOAD_FOLDER`], so `location` only changes on
+/// [`WriterRequest::Complete`], and that file is removed again if the upload is
+/// aborted or dropped. If the stream cannot be opened, every request is answered with
+/// that error.
+pub(crate) fn open_file_writer(rx: UnboundedReceiver<WriterRequest>, location: Path) {
+    wasm_bindgen_futures::spawn_local({
+        let mut rx = rx;
+        async move {
+            let writer = FileWriterState::open(&location).await;
 
-    let csv_reader = ReaderBuilder::new(Arc::new(schema.clone()))
-        .with_format(csv_format)
-        .build(bytes_cursor)
-        .unwrap();
+            while let Some(request) = rx.next().await {
+                let writer = match &writer {
+                    Ok(writer) => writer,
+                    Err(e) => {
+                        match request {
+                            WriterRequest::Part(_, ack) | WriterRequest::Abort(ack) => {
+                                let _ = ack.send(Err(e.clone()));
+                            }
+                            WriterRequest::Complete(tx) => {
+                                let _ = tx.send(Err(e.clone()));
+                            }
+                        }
+                        continue;
+                    }
+                };
+                match request {
+                    WriterRequest::Part(payload, ack) => {
+                        let _ = ack.send(writer.write(&location, payload).await);
+                    }
+                    WriterRequest::Complete(tx) => {
+                        let _ = tx.send(writer.complete(&location).await);
+                        return;
+                    }
+                    WriterRequest::Abort(ack) => {
+                        let _ = ack.send(writer.discard(&location).await);
+                        return;
+                    }
+                }
+            }
+            // the upload was dropped without being completed
+            if let Ok(writer) = writer {
+                let _ = writer.discard(&location).await;
+            }
+        }
+    });
+}
 
-    let mut output: Vec<u8> = Vec::new();
+pub(crate) fn delete_file(tx: Sender<Result<(), OpfsError>>, location: Path) {
+    wasm_bindgen_futures::spawn_local({
+        async move {
+            let _ = tx.send(remove_file(&location).await.for_path(&location));
+        }
+    });
+}
 
-    let options =
-        IpcWriteOptions::try_new(8, false, MetadataVersion::V5)?;
-    let mut writer =
-        FileWriter::try_new_with_options(&mut output, &schema.clone(), options).unwrap();
+async fn remove_file(location: &Path) -> Result<(), JsValue> {
+    // moving Window as ref from the static async context to prevent loss of context
+    let window: Window = get_window()?;
+    let (folder, name) = get_parent_folder(&window, location, false).await?;
+    // makes sure a folder of that name is not removed instead
+    try_get_from_promise::<FileSystemFileHandle>(folder.get_file_handle(name.as_str())).await?;
+    JsFuture::from(folder.remove_entry(name.as_str())).await?;
+    Ok(())
+}
 
-    for batch in csv_reader {
-        match batch {
-            Ok(batch) => writer.write(&batch)?,
-            Err(error) => return Err(error),
+/// Copies the file at `from` to `to` without loading it into memory. The target only
+/// changes once the copy is complete, so a replaced file is never seen half written.
+/// With `rename` the source is moved instead, using `FileSystemHandle.move` where the
+/// browser supports it.
+pub(crate) fn copy_file(tx: Sender<Result<(), OpfsError>>, from: Path, to: Path, rename: bool) {
+    wasm_bindgen_futures::spawn_local({
+        async move {
+            let _ = tx.send(copy_file_inner(&from, &to, rename).await);
         }
+    });
+}
+
+async fn copy_file_inner(from: &Path, to: &Path, rename: bool) -> Result<(), OpfsError> {
+    // moving Window as ref from the static async context to prevent loss of context
+    let window: Window = get_window().for_path(from)?;
+    let source = get_file_handle(&window, from).await.for_path(from)?;
+    // the fallback below removes the source after writing the target
+    if from == to {
+        return Ok(());
+    }
+    let (target_folder, target_name) = get_parent_folder(&window, to, true).await.for_path(to)?;
+    if rename && move_file(&source, &target_folder, &target_name).await {
+        return Ok(());
     }
-    writer.close().unwrap();
 
-    write_arrow_to_file(output, name).await;
+    let file = try_get_from_promise::<File>(source.get_file())
+        .await
+        .for_path(from)?;
+    write_blob(&target_folder, &target_name, &file, to).await?;
 
+    if rename {
+        remove_file(from).await.for_path(from)?;
+    }
     Ok(())
 }
 
-pub async fn write_arrow_to_file(output: Vec<u8>, name: String) {
-    let option_arrow = &FileSystemGetFileOptions::default();
-    option_arrow.set_create(true);
-
-    let arrow_name = format!("{name}.arrow");
-    let window: Window = window().unwrap();
-    let import_handle = get_file_folder(&window).await;
-    let arrow_file_handle = get_from_promise::<FileSystemFileHandle>(
-        import_handle.get_file_handle_with_options(&arrow_name.as_str(), &option_arrow),
+/// Writes `blob` to the file `name` in `folder`, which is replaced once the write is
+/// complete
+async fn write_blob(
+    folder: &FileSystemDirectoryHandle,
+    name: &str,
+    blob: &Blob,
+    location: &Path,
+) -> Result<(), OpfsError> {
+    let options = &FileSystemGetFileOptions::default();
+    options.set_create(true);
+    let target = try_get_from_promise::<FileSystemFileHandle>(
+        folder.get_file_handle_with_options(name, options),
     )
-    .await;
-
+    .await
+    .for_path(location)?;
     let write_file_stream =
-        get_from_promise::<FileSystemWritableFileStream>(arrow_file_handle.create_writable()).await;
-
-    JsFuture::from(write_file_stream.write_with_u8_array(&output).unwrap())
+        try_get_from_promise::<FileSystemWritableFileStream>(target.create_writable())
+            .await
+            .for_path(location)?;
+    let written = match write_file_stream.write_with_blob(blob) {
+        Ok(promise) => JsFuture::from(promise).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        let _ = JsFuture::from(write_file_stream.abort()).await;
+        return Err(OpfsError::from_js(location, e));
+    }
+    JsFuture::from(write_file_stream.close())
         .await
-        .unwrap();
-    JsFuture::from(write_file_stream.close()).await.unwrap();
+        .for_path(location)?;
+    Ok(())
+}
+
+/// `FileSystemHandle.move` is missing from web-sys and not implemented by every browser,
+/// so it is looked up at runtime. Returns `false` if the file could not be moved.
+async fn move_file(
+    handle: &FileSystemFileHandle,
+    folder: &FileSystemDirectoryHandle,
+    name: &str,
+) -> bool {
+    let move_fn = match Reflect::get(handle, &JsValue::from_str("move")) {
+        Ok(move_fn) if move_fn.is_function() => move_fn.unchecked_into::<Function>(),
+        _ => return false,
+    };
+    match move_fn.call2(handle, folder, &JsValue::from_str(name)) {
+        Ok(promise) => JsFuture::from(Promise::from(promise)).await.is_ok(),
+        Err(_) => false,
+    }
 }
 
-pub fn get_file_data(tx: Sender<FileResponse>, name: String, head: bool) {
+/// Reads `ranges` of the file at `location`, slicing the `File` so only the requested
+/// bytes are loaded. Ranges are clamped to the file size, an empty `ranges` only
+/// fetches the metadata.
+pub(crate) fn get_file_data(
+    tx: Sender<Result<FileResponse, OpfsError>>,
+    location: Path,
+    ranges: Vec<GetRange>,
+) {
     wasm_bindgen_futures::spawn_local({
-        let f_name = name;
         async move {
-            // moving Window as ref from the static async context to prevent loss of context
-            let window: Window = window().unwrap();
-            let import_handle = get_file_folder(&window).await;
-            let file_handle = get_from_promise::<FileSystemFileHandle>(
-                import_handle.get_file_handle(f_name.as_str()),
-            )
-            .await;
-            let csv_file = get_from_promise::<File>(file_handle.get_file()).await;
-            let csv_bytes = if head {
-                None
-            } else {
-                let bytes = JsFuture::from(csv_file.array_buffer())
-                    .map(|value| match value {
-                        Ok(value) => {
-                            let u8_arr = Uint8Array::new(&value);
-                            Ok(Bytes::from(u8_arr.to_vec()))
-                        }
-                        Err(e) => Err(e),
-                    })
-                    .await
-                    .unwrap();
-                Some(bytes)
-            };
-            let milliseconds_since: i64 = csv_file.last_modified() as i64;
-            let time = DateTime::from_timestamp_millis(milliseconds_since).unwrap();
-            let resp = FileResponse {
-                bytes: csv_bytes,
-                name: csv_file.name(),
-                last_modified: time,
-                size: csv_file.size() as u64,
+            let _ = tx.send(read_file_data(&location, ranges).await.for_path(&location));
+        }
+    });
+}
+
+async fn read_file_data(location: &Path, ranges: Vec<GetRange>) -> Result<FileResponse, JsValue> {
+    // moving Window as ref from the static async context to prevent loss of context
+    let window: Window = get_window()?;
+    let file_handle = get_file_handle(&window, location).await?;
+    let file = try_get_from_promise::<File>(file_handle.get_file()).await?;
+    let mut file_bytes = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        let blob = slice_file(&file, &range)?;
+        let value = JsFuture::from(blob.array_buffer()).await?;
+        file_bytes.push(Bytes::from(Uint8Array::new(&value).to_vec()));
+    }
+    Ok(FileResponse::from_file(&file, file_bytes))
+}
+
+/// Like [`get_file_data`], but instead of loading the range at once its content is
+/// sent to `chunks` piece by piece, at most [`STREAM_CHUNK_SIZE`] bytes at a time.
+/// The metadata is sent to `tx` before the first chunk. Reading stops as soon as
+/// `chunks` is dropped, so only a bounded part of the file is held in memory.
+pub(crate) fn stream_file_data(
+    tx: Sender<Result<FileResponse, OpfsError>>,
+    chunks: mpsc::Sender<Result<Bytes, OpfsError>>,
+    location: Path,
+    range: Option<GetRange>,
+) {
+    wasm_bindgen_futures::spawn_local({
+        let mut chunks = chunks;
+        async move {
+            let opened = open_file_stream(&location, range).await;
+            let (file, reader) = match opened.for_path(&location) {
+                Ok(opened) => opened,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
             };
-            tx.send(resp).unwrap();
+            if tx
+                .send(Ok(FileResponse::from_file(&file, Vec::new())))
+                .is_err()
+            {
+                return;
+            }
+
+            loop {
+                let result = match JsFuture::from(reader.read()).await {
+                    Ok(result) => result.unchecked_into::<ReadableStreamReadResult>(),
+                    Err(e) => {
+                        let _ = chunks.send(Err(OpfsError::from_js(&location, e))).await;
+                        return;
+                    }
+                };
+                if result.get_done().unwrap_or(true) {
+                    return;
+                }
+                let chunk = Uint8Array::new(&result.get_value());
+                let mut offset = 0;
+                while offset < chunk.length() {
+                    let end = chunk.length().min(offset + STREAM_CHUNK_SIZE);
+                    let bytes = Bytes::from(chunk.subarray(offset, end).to_vec());
+                    if chunks.send(Ok(bytes)).await.is_err() {
+                        // the consumer went away, stop reading the file
+                        let _ = JsFuture::from(reader.cancel()).await;
+                        return;
+                    }
+                    offset = end;
+                }
+            }
         }
     });
 }
 
-pub fn get_files(tx: std::sync::mpsc::Sender<ObjectMeta>) {
+async fn open_file_stream(
+    location: &Path,
+    range: Option<GetRange>,
+) -> Result<(File, ReadableStreamDefaultReader), JsValue> {
+    // moving Window as ref from the static async context to prevent loss of context
+    let window: Window = get_window()?;
+    let file_handle = get_file_handle(&window, location).await?;
+    let file = try_get_from_promise::<File>(file_handle.get_file()).await?;
+    let blob = slice_file(&file, &range.unwrap_or(GetRange::Offset(0)))?;
+    let reader = ReadableStreamDefaultReader::new(&blob.stream())?;
+    Ok((file, reader))
+}
+
+/// Blob.slice clamps out of bounds offsets to the file size
+fn slice_file(file: &File, range: &GetRange) -> Result<Blob, JsValue> {
+    let size = file.size() as u64;
+    let (start, end) = match range {
+        GetRange::Bounded(r) => (r.start, r.end),
+        GetRange::Offset(o) => (*o, size),
+        GetRange::Suffix(n) => (size.saturating_sub(*n), size),
+    };
+    file.slice_with_f64_and_f64(start as f64, end as f64)
+}
+
+/// Walks the data folder, including nested folders, and sends every file whose path
+/// matches `prefix` to `tx`, together with its path relative to the data folder.
+/// Folders that cannot contain a match are not entered.
+pub(crate) fn get_files(
+    tx: mpsc::Sender<Result<(Path, FileResponse), OpfsError>>,
+    prefix: Option<Path>,
+) {
     wasm_bindgen_futures::spawn_local({
+        let mut tx = tx;
         async move {
-            // moving Window as ref from the static async context to prevent loss of context
-            let window: Window = web_sys::window().unwrap();
-            let import_handle = get_file_folder(&window).await;
-
-            let iterator = try_iter(&import_handle.values())
-                .unwrap()
-                .ok_or_else(|| "need to pass iterable JS values!")
-                .unwrap();
-
-            for value in iterator {
-                let value = value.unwrap();
-                assert!(value.has_type::<FileSystemFileHandle>());
-                let file_handle = value.unchecked_into::<FileSystemFileHandle>();
-                let file = get_from_promise::<File>(file_handle.get_file()).await;
-                let mut path_str = "opfs://data/".to_owned();
-                path_str.push_str(file.name().as_str());
-                let milliseconds_since: i64 = file.last_modified() as i64;
-                let time = DateTime::from_timestamp_millis(milliseconds_since);
-                let meta = ObjectMeta {
-                    location: Path::parse(path_str).unwrap(),
-                    last_modified: time.unwrap(),
-                    size: file.size() as u64,
-                    e_tag: None,
-                    version: None,
+            let root = Path::default();
+            let mut folders = match get_data_folder().await {
+                Ok(import_handle) => vec![(import_handle, root)],
+                Err(e) => {
+                    let _ = tx.send(Err(OpfsError::from_js(&root, e))).await;
+                    return;
+                }
+            };
+
+            while let Some((folder, folder_path)) = folders.pop() {
+                let handles = match read_folder(&folder).await {
+                    Ok(handles) => handles,
+                    Err(e) => {
+                        let _ = tx.send(Err(OpfsError::from_js(&folder_path, e))).await;
+                        return;
+                    }
                 };
-                tx.send(meta).unwrap();
+                for handle in handles {
+                    let name = handle.name();
+                    let part = match path_part(&name) {
+                        Some(part) if is_data(&folder_path, &name) => part,
+                        _ => continue,
+                    };
+                    let location = folder_path.child(part);
+                    match handle.dyn_into::<FileSystemFileHandle>() {
+                        Ok(file_handle) => {
+                            if !prefix.as_ref().is_none_or(|p| location.prefix_matches(p)) {
+                                continue;
+                            }
+                            let entry = try_get_from_promise::<File>(file_handle.get_file())
+                                .await
+                                .map(|file| {
+                                    (location.clone(), FileResponse::from_file(&file, Vec::new()))
+                                })
+                                .for_path(&location);
+                            if tx.send(entry).await.is_err() {
+                                // the listing was dropped, no need to walk any further
+                                return;
+                            }
+                        }
+                        Err(handle) => {
+                            let descend = prefix.as_ref().is_none_or(|p| {
+                                location.prefix_matches(p) || p.prefix_matches(&location)
+                            });
+                            if descend {
+                                folders.push((handle.unchecked_into(), location));
+                            }
+                        }
+                    }
+                }
             }
         }
     });
 }
+
+/// Lists the files and folders directly inside the folder at `prefix`, a missing folder
+/// is reported as empty
+pub(crate) fn get_folder_entries(tx: Sender<Result<FolderResponse, OpfsError>>, prefix: Path) {
+    wasm_bindgen_futures::spawn_local({
+        async move {
+            let resp = match read_folder_entries(&prefix).await.for_path(&prefix) {
+                Err(OpfsError::NotFound { .. }) | Err(OpfsError::TypeMismatch { .. }) => {
+                    Ok(FolderResponse::default())
+                }
+                resp => resp,
+            };
+            let _ = tx.send(resp);
+        }
+    });
+}
+
+async fn read_folder_entries(prefix: &Path) -> Result<FolderResponse, JsValue> {
+    // moving Window as ref from the static async context to prevent loss of context
+    let window: Window = get_window()?;
+    let folder = get_folder(&window, prefix).await?;
+    let mut resp = FolderResponse::default();
+    for handle in read_folder(&folder).await? {
+        let name = handle.name();
+        let part = match path_part(&name) {
+            Some(part) if is_data(prefix, &name) => part,
+            _ => continue,
+        };
+        let location = prefix.child(part);
+        match handle.dyn_into::<FileSystemFileHandle>() {
+            Ok(file_handle) => {
+                let file = try_get_from_promise::<File>(file_handle.get_file()).await?;
+                resp.files
+                    .push((location, FileResponse::from_file(&file, Vec::new())));
+            }
+            Err(_) => resp.folders.push(location),
+        }
+    }
+    Ok(resp)
+}
@@ -4,44 +4,73 @@
 
 extern crate wasm_bindgen_test;
 use std::assert_eq;
+use std::ops::Range;
+use std::sync::Arc;
 
+use datafusion::arrow::array::{ArrayRef, Int64Array};
 use datafusion::arrow::ipc::reader::StreamReader;
 use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::parquet::arrow::ArrowWriter;
+use futures::StreamExt;
 use js_sys::Uint8Array;
-use proto_query_engine::web_fs_utils::{get_file_folder, get_from_promise};
-use proto_query_engine::{register_csv, run_sql};
+use object_store::{
+    path::Path, Error as StoreError, GetOptions, GetRange, MultipartUpload, ObjectStore, PutMode,
+    UpdateVersion,
+};
+use proto_query_engine::opfs_store::OpfsFileSystem;
+use proto_query_engine::session::QueryEngine;
+use proto_query_engine::web_fs_utils::{
+    try_get_file_folder, try_get_from_promise, STREAM_CHUNK_SIZE,
+};
+use proto_query_engine::{
+    execute, explain_sql, load_csv_bytes, load_csv_file, load_json_bytes, load_parquet_bytes,
+    persist_sql, prepare, preview_csv, register_csv, register_json, register_parquet,
+    register_table, run_sql, run_sql_stream, sniff_csv,
+};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_test::{console_log, *};
-use web_sys::{FileSystemFileHandle, FileSystemGetFileOptions, FileSystemWritableFileStream, Window};
+use web_sys::{
+    FileSystemDirectoryHandle, FileSystemFileHandle, FileSystemGetDirectoryOptions,
+    FileSystemGetFileOptions, FileSystemWritableFileStream, Window,
+};
 
 wasm_bindgen_test_configure!(run_in_browser);
 
-async fn set_up() -> Result<(), JsError> {
+async fn set_up() -> Result<(), JsValue> {
     let window: Window = web_sys::window().unwrap();
-    let import_handle = get_file_folder(&window).await;
+    let import_handle = try_get_file_folder(&window).await?;
     let options = &FileSystemGetFileOptions::new();
     options.set_create(true);
-    let import_file = get_from_promise::<FileSystemFileHandle>(
-        import_handle
-            .get_file_handle_with_options("12test2.csv", options)
+    let import_file = try_get_from_promise::<FileSystemFileHandle>(
+        import_handle.get_file_handle_with_options("12test2.csv", options),
     )
-    .await;
+    .await?;
     let writable =
-        get_from_promise::<FileSystemWritableFileStream>(import_file.create_writable()).await;
-    let _ = writable.write_with_str("a,b,c\n1,2,3").unwrap();
-    let _ = writable.close();
+        try_get_from_promise::<FileSystemWritableFileStream>(import_file.create_writable()).await?;
+    try_get_from_promise::<JsValue>(writable.write_with_str("a,b,c\n1,2,3")?).await?;
+    try_get_from_promise::<JsValue>(writable.close()).await?;
     Ok(())
 }
 
+/// Decodes the Arrow IPC stream returned by `run_sql` and the other query functions
+fn read_ipc(result: &JsValue) -> Vec<RecordBatch> {
+    let bytes: Vec<u8> = Uint8Array::new(result).to_vec();
+    let reader = StreamReader::try_new(&bytes[..], None).unwrap();
+    reader.map(|batch| batch.unwrap()).collect()
+}
+
 #[wasm_bindgen_test]
 async fn pass() {
     let _set_up = set_up().await;
-    let _add_result = register_csv("12test2.csv".to_string(), "test".to_string()).await;
-    let result =
-        run_sql("SELECT a, min(b) FROM test WHERE a <= b GROUP BY a LIMIT 100".to_string()).await;
+    let _add_result = register_csv("12test2.csv".to_string(), "test".to_string(), None).await;
+    let result = run_sql(
+        "SELECT a, min(b) FROM test WHERE a <= b GROUP BY a LIMIT 100".to_string(),
+        None,
+    )
+    .await;
 
     let js_value = JsValue::from(result.clone().err());
-    let ok_value = JsValue::from(result.ok().unwrap());
+    let ok_value = result.ok().unwrap();
     let arr = Uint8Array::new(&ok_value);
     let arr_vec: Vec<u8> = arr.to_vec();
     let reader = StreamReader::try_new(&arr_vec[..], None).unwrap();
@@ -55,7 +84,7 @@ async fn pass() {
     assert_eq!(JsValue::undefined(), js_value);
 
     datafusion::assert_batches_eq!(
-        vec![
+        [
             "+---+-------------+",
             "| a | min(test.b) |",
             "+---+-------------+",
@@ -65,3 +94,1506 @@ async fn pass() {
         &results
     );
 }
+
+#[wasm_bindgen_test]
+async fn copy_to_opfs() {
+    let copy_result = run_sql(
+        "COPY (SELECT 1 AS a, 2 AS b) TO 'opfs:///copy_target.csv' STORED AS CSV".to_string(),
+        None,
+    )
+    .await;
+    assert!(copy_result.is_ok());
+
+    register_csv("copy_target".to_string(), "copy_target".to_string(), None)
+        .await
+        .unwrap();
+    let result = run_sql("SELECT a, b FROM copy_target".to_string(), None)
+        .await
+        .unwrap();
+    let results = read_ipc(&result);
+
+    datafusion::assert_batches_eq!(
+        [
+            "+---+---+",
+            "| a | b |",
+            "+---+---+",
+            "| 1 | 2 |",
+            "+---+---+",
+        ],
+        &results
+    );
+}
+
+#[wasm_bindgen_test]
+async fn put_opts_modes() {
+    let store = OpfsFileSystem::new();
+    let location = Path::from("put_modes.txt");
+    let _ = store.delete(&location).await;
+
+    // an update needs an existing file
+    let update = PutMode::Update(UpdateVersion {
+        e_tag: Some("0-0".to_string()),
+        version: None,
+    });
+    let missing = store.put_opts(&location, "one".into(), update.into()).await;
+    assert!(
+        matches!(missing, Err(StoreError::Precondition { .. })),
+        "{:?}",
+        missing
+    );
+
+    let created = store
+        .put_opts(&location, "one".into(), PutMode::Create.into())
+        .await
+        .unwrap();
+    let exists = store
+        .put_opts(&location, "two".into(), PutMode::Create.into())
+        .await;
+    assert!(
+        matches!(exists, Err(StoreError::AlreadyExists { .. })),
+        "{:?}",
+        exists
+    );
+
+    // back to back writes of the same size still change the ETag
+    let current = PutMode::Update(UpdateVersion {
+        e_tag: created.e_tag.clone(),
+        version: None,
+    });
+    let updated = store
+        .put_opts(&location, "two".into(), current.into())
+        .await
+        .unwrap();
+    assert_ne!(updated.e_tag, created.e_tag);
+    let stale = PutMode::Update(UpdateVersion {
+        e_tag: created.e_tag,
+        version: None,
+    });
+    let outdated = store.put_opts(&location, "six".into(), stale.into()).await;
+    assert!(
+        matches!(outdated, Err(StoreError::Precondition { .. })),
+        "{:?}",
+        outdated
+    );
+
+    store
+        .put_opts(&location, "five".into(), PutMode::Overwrite.into())
+        .await
+        .unwrap();
+    let bytes = store.get(&location).await.unwrap().bytes().await.unwrap();
+    assert_eq!(&bytes[..], b"five");
+}
+
+#[wasm_bindgen_test]
+async fn get_ranges_of_a_file() {
+    let store = OpfsFileSystem::new();
+    let location = Path::from("ranges.bin");
+    let data: Vec<u8> = (0..=255).collect();
+    store.put(&location, data.clone().into()).await.unwrap();
+
+    // out of order, overlapping and close enough to be fetched together
+    let ranges = [200..256, 0..10, 5..15, 12..20, 100..101];
+    let bytes = store.get_ranges(&location, &ranges).await.unwrap();
+    assert_eq!(bytes.len(), ranges.len());
+    for (range, bytes) in ranges.iter().zip(&bytes) {
+        assert_eq!(&bytes[..], &data[range.start as usize..range.end as usize]);
+    }
+
+    let beyond = store.get_range(&location, 300..310).await;
+    assert!(beyond.is_err());
+
+    // an inverted range is an error, not a panic
+    let inverted = Range { start: 10, end: 5 };
+    let inverted = store.get_ranges(&location, &[0..10, inverted]).await;
+    assert!(
+        matches!(inverted, Err(StoreError::Generic { .. })),
+        "{:?}",
+        inverted
+    );
+}
+
+#[wasm_bindgen_test]
+async fn get_opts_streams_large_files() {
+    let store = OpfsFileSystem::new();
+    let location = Path::from("stream.bin");
+    let chunk_size = STREAM_CHUNK_SIZE as u64;
+    // two and a half chunks
+    let data: Vec<u8> = (0..chunk_size * 5 / 2).map(|i| (i % 251) as u8).collect();
+    store.put(&location, data.clone().into()).await.unwrap();
+
+    let mut stream = store.get(&location).await.unwrap().into_stream();
+    let mut chunks = 0;
+    let mut read = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        read.extend_from_slice(&chunk.unwrap());
+        chunks += 1;
+    }
+    assert!(chunks >= 3, "{:?}", chunks);
+    assert_eq!(read, data);
+
+    // a range across the end of the first chunk
+    let range = chunk_size - 500..chunk_size + 500;
+    let options = GetOptions {
+        range: Some(GetRange::Bounded(range.clone())),
+        ..Default::default()
+    };
+    let result = store.get_opts(&location, options).await.unwrap();
+    assert_eq!(result.range, range);
+    let bytes = result.bytes().await.unwrap();
+    assert_eq!(&bytes[..], &data[range.start as usize..range.end as usize]);
+
+    let options = GetOptions {
+        range: Some(GetRange::Suffix(10)),
+        ..Default::default()
+    };
+    let bytes = store
+        .get_opts(&location, options)
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    assert_eq!(&bytes[..], &data[data.len() - 10..]);
+
+    // a head request returns the metadata without the content
+    let options = GetOptions {
+        head: true,
+        ..Default::default()
+    };
+    let result = store.get_opts(&location, options).await.unwrap();
+    assert_eq!(result.meta.size, data.len() as u64);
+    let e_tag = result.meta.e_tag.clone();
+    let last_modified = result.meta.last_modified;
+    assert!(result.bytes().await.unwrap().is_empty());
+
+    let options = GetOptions {
+        if_match: e_tag.clone(),
+        if_unmodified_since: Some(last_modified),
+        range: Some(GetRange::Bounded(0..10)),
+        ..Default::default()
+    };
+    let bytes = store
+        .get_opts(&location, options)
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    assert_eq!(&bytes[..], &data[..10]);
+
+    let options = GetOptions {
+        if_none_match: e_tag,
+        ..Default::default()
+    };
+    let unchanged = store.get_opts(&location, options).await;
+    assert!(
+        matches!(unchanged, Err(StoreError::NotModified { .. })),
+        "{:?}",
+        unchanged
+    );
+    let options = GetOptions {
+        if_match: Some("outdated".to_string()),
+        ..Default::default()
+    };
+    let changed = store.get_opts(&location, options).await;
+    assert!(
+        matches!(changed, Err(StoreError::Precondition { .. })),
+        "{:?}",
+        changed
+    );
+    let options = GetOptions {
+        if_unmodified_since: Some(last_modified - chrono::Duration::seconds(1)),
+        ..Default::default()
+    };
+    let modified = store.get_opts(&location, options).await;
+    assert!(
+        matches!(modified, Err(StoreError::Precondition { .. })),
+        "{:?}",
+        modified
+    );
+}
+
+#[wasm_bindgen_test]
+async fn list_honours_prefix() {
+    let store = OpfsFileSystem::new();
+    for location in [
+        "listing/a.txt",
+        "listing/nested/b.txt",
+        "listing/nested/deeper/c.txt",
+        "listing_sibling.txt",
+        ".upload-notes.txt",
+    ] {
+        store.put(&Path::from(location), "x".into()).await.unwrap();
+    }
+
+    let prefix = Path::from("listing");
+    let mut listed: Vec<String> = store
+        .list(Some(&prefix))
+        .map(|meta| meta.unwrap().location.to_string())
+        .collect()
+        .await;
+    listed.sort();
+    assert_eq!(
+        listed,
+        [
+            "listing/a.txt",
+            "listing/nested/b.txt",
+            "listing/nested/deeper/c.txt"
+        ]
+    );
+
+    let nested: Vec<String> = store
+        .list(Some(&Path::from("listing/nested/deeper")))
+        .map(|meta| meta.unwrap().location.to_string())
+        .collect()
+        .await;
+    assert_eq!(nested, ["listing/nested/deeper/c.txt"]);
+
+    let result = store.list_with_delimiter(Some(&prefix)).await.unwrap();
+    let objects: Vec<String> = result
+        .objects
+        .iter()
+        .map(|meta| meta.location.to_string())
+        .collect();
+    assert_eq!(objects, ["listing/a.txt"]);
+    assert_eq!(result.common_prefixes, [Path::from("listing/nested")]);
+
+    // the workspace catalogs and unfinished uploads are not part of the data, user files
+    // with a similar name are
+    let all: Vec<String> = store
+        .list(None)
+        .map(|meta| meta.unwrap().location.to_string())
+        .collect()
+        .await;
+    assert!(all.contains(&"listing_sibling.txt".to_string()));
+    assert!(all.contains(&".upload-notes.txt".to_string()));
+    assert!(!all.iter().any(|location| location.starts_with(".catalog/")));
+    assert!(!all.iter().any(|location| location.starts_with(".uploads/")));
+}
+
+#[wasm_bindgen_test]
+async fn delete_copy_and_rename() {
+    let store = OpfsFileSystem::new();
+    let source = Path::from("moves/source.txt");
+    let copied = Path::from("moves/copied.txt");
+    let renamed = Path::from("moves/nested/renamed.txt");
+    for location in [&copied, &renamed] {
+        let _ = store.delete(location).await;
+    }
+    store.put(&source, "data".into()).await.unwrap();
+
+    store.copy(&source, &copied).await.unwrap();
+    let bytes = store.get(&copied).await.unwrap().bytes().await.unwrap();
+    assert_eq!(&bytes[..], b"data");
+
+    let exists = store.copy_if_not_exists(&source, &copied).await;
+    assert!(
+        matches!(exists, Err(StoreError::AlreadyExists { .. })),
+        "{:?}",
+        exists
+    );
+    let exists = store.rename_if_not_exists(&source, &copied).await;
+    assert!(
+        matches!(exists, Err(StoreError::AlreadyExists { .. })),
+        "{:?}",
+        exists
+    );
+    // the failed rename leaves the source in place
+    store.head(&source).await.unwrap();
+
+    store.rename_if_not_exists(&source, &renamed).await.unwrap();
+    let missing = store.head(&source).await;
+    assert!(
+        matches!(missing, Err(StoreError::NotFound { .. })),
+        "{:?}",
+        missing
+    );
+    let bytes = store.get(&renamed).await.unwrap().bytes().await.unwrap();
+    assert_eq!(&bytes[..], b"data");
+
+    // copy and rename replace an existing target
+    store.put(&source, "other".into()).await.unwrap();
+    store.rename(&source, &copied).await.unwrap();
+    let bytes = store.get(&copied).await.unwrap().bytes().await.unwrap();
+    assert_eq!(&bytes[..], b"other");
+
+    // moving a file onto itself keeps it
+    store.copy(&copied, &copied).await.unwrap();
+    store.rename(&copied, &copied).await.unwrap();
+    let bytes = store.get(&copied).await.unwrap().bytes().await.unwrap();
+    assert_eq!(&bytes[..], b"other");
+
+    store.delete(&copied).await.unwrap();
+    let missing = store.head(&copied).await;
+    assert!(
+        matches!(missing, Err(StoreError::NotFound { .. })),
+        "{:?}",
+        missing
+    );
+}
+
+#[wasm_bindgen_test]
+async fn multipart_uploads_appear_on_complete() {
+    let store = OpfsFileSystem::new();
+    let folder = Path::from("multipart");
+    let location = folder.child("upload.txt");
+    let _ = store.delete(&location).await;
+
+    let mut upload = store.put_multipart(&location).await.unwrap();
+    upload.put_part("first,".into()).await.unwrap();
+    upload.put_part("second".into()).await.unwrap();
+    // neither the target nor the unfinished upload can be seen yet
+    let missing = store.head(&location).await;
+    assert!(
+        matches!(missing, Err(StoreError::NotFound { .. })),
+        "{:?}",
+        missing
+    );
+    let listed: Vec<_> = store.list(Some(&folder)).collect().await;
+    assert!(listed.is_empty(), "{:?}", listed);
+
+    let completed = upload.complete().await.unwrap();
+    let result = store.get(&location).await.unwrap();
+    assert_eq!(completed.e_tag, result.meta.e_tag);
+    assert_eq!(&result.bytes().await.unwrap()[..], b"first,second");
+
+    // an aborted upload leaves the target as it was
+    let mut aborted = store.put_multipart(&location).await.unwrap();
+    aborted.put_part("replaced".into()).await.unwrap();
+    aborted.abort().await.unwrap();
+    let bytes = store.get(&location).await.unwrap().bytes().await.unwrap();
+    assert_eq!(&bytes[..], b"first,second");
+    let listed: Vec<String> = store
+        .list(Some(&folder))
+        .map(|meta| meta.unwrap().location.to_string())
+        .collect()
+        .await;
+    assert_eq!(listed, ["multipart/upload.txt"]);
+}
+
+#[wasm_bindgen_test]
+async fn hive_partitioned_table() {
+    run_sql(
+        "COPY (SELECT * FROM (VALUES (2020, 'polluted', 1), (2021, 'clean', 2)) AS t(year, site, val)) \
+         TO 'opfs:///hive/' STORED AS CSV PARTITIONED BY (year, site)"
+            .to_string(),
+        None,
+    )
+    .await
+    .unwrap();
+    run_sql(
+        "CREATE EXTERNAL TABLE hive (val BIGINT, year INT, site VARCHAR) STORED AS CSV \
+         PARTITIONED BY (year, site) LOCATION 'opfs:///hive/' OPTIONS ('format.has_header' 'true')"
+            .to_string(),
+        None,
+    )
+    .await
+    .unwrap();
+    let result = run_sql(
+        "SELECT year, site, val FROM hive ORDER BY year".to_string(),
+        None,
+    )
+    .await
+    .unwrap();
+    let results = read_ipc(&result);
+
+    datafusion::assert_batches_eq!(
+        [
+            "+------+----------+-----+",
+            "| year | site     | val |",
+            "+------+----------+-----+",
+            "| 2020 | polluted | 1   |",
+            "| 2021 | clean    | 2   |",
+            "+------+----------+-----+",
+        ],
+        &results
+    );
+}
+
+#[wasm_bindgen_test]
+async fn missing_file_is_an_error() {
+    let result = register_table("missing_digest".to_string(), "missing".to_string()).await;
+    assert_eq!(result.err().unwrap().kind(), "io");
+}
+
+#[wasm_bindgen_test]
+async fn sql_errors_are_classified() {
+    let error = run_sql("SELECT 1 FROM\nWHERE".to_string(), None)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), "parse");
+    assert_eq!(error.line(), Some(2));
+    assert!(!error.chain().is_empty());
+
+    let error = run_sql("SELECT * FROM no_such_table".to_string(), None)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), "plan");
+}
+
+#[wasm_bindgen_test]
+async fn sessions_have_separate_catalogs() {
+    let first = QueryEngine::new();
+    let second = QueryEngine::new();
+    first
+        .run_sql("CREATE TABLE notes AS VALUES (1)".to_string(), None)
+        .await
+        .unwrap();
+
+    assert!(first
+        .run_sql("SELECT * FROM notes".to_string(), None)
+        .await
+        .is_ok());
+    let error = second
+        .run_sql("SELECT * FROM notes".to_string(), None)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), "plan");
+}
+
+#[wasm_bindgen_test]
+async fn stream_query_batches() {
+    let engine = QueryEngine::new();
+    engine
+        .set_option(
+            "datafusion.execution.batch_size".to_string(),
+            "2".to_string(),
+        )
+        .unwrap();
+    let raw = engine
+        .run_sql_stream(
+            "SELECT * FROM (VALUES (1), (2), (3)) AS t(a)".to_string(),
+            None,
+        )
+        .await
+        .unwrap();
+    let chunks: Vec<Vec<u8>> = wasm_streams::ReadableStream::from_raw(raw)
+        .into_stream()
+        .map(|chunk| Uint8Array::new(&chunk.unwrap()).to_vec())
+        .collect()
+        .await;
+    // schema, at least one batch and the end of stream marker
+    assert!(chunks.len() >= 3);
+
+    let bytes = chunks.concat();
+    let reader = StreamReader::try_new(&bytes[..], None).unwrap();
+    let results: Vec<RecordBatch> = reader.map(|batch| batch.unwrap()).collect();
+    assert_eq!(
+        results.iter().map(|batch| batch.num_rows()).sum::<usize>(),
+        3
+    );
+
+    let error = run_sql_stream("SELECT * FROM nowhere".to_string(), None)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), "plan");
+}
+
+#[wasm_bindgen_test]
+async fn cancelled_queries_reject() {
+    let options = js_sys::Object::new();
+    let signal = web_sys::AbortSignal::abort_with_reason(&JsValue::from_str("closed tab"));
+    js_sys::Reflect::set(&options, &JsValue::from_str("signal"), &signal).unwrap();
+    let error = run_sql("SELECT 1".to_string(), Some(options))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), "cancelled");
+    assert_eq!(error.message(), "Query cancelled: closed tab");
+
+    let options = js_sys::Object::new();
+    js_sys::Reflect::set(&options, &JsValue::from_str("timeout"), &JsValue::from(20)).unwrap();
+    let error = run_sql(
+        "WITH t AS (SELECT * FROM (VALUES (1), (2), (3), (4), (5), (6), (7), (8), (9), (10)) AS v(x)) \
+         SELECT a.x FROM t a, t b, t c, t d, t e, t f, t g, t h"
+            .to_string(),
+        Some(options),
+    )
+    .await
+    .err()
+    .unwrap();
+    assert_eq!(error.kind(), "cancelled");
+}
+
+#[wasm_bindgen_test]
+async fn progress_is_reported() {
+    use std::{cell::RefCell, rc::Rc};
+
+    let engine = QueryEngine::new();
+    engine
+        .run_sql(
+            "COPY (SELECT * FROM (VALUES (1), (2), (3)) AS t(a)) TO 'opfs:///progress.csv' STORED AS CSV"
+                .to_string(),
+            None,
+        )
+        .await
+        .unwrap();
+    engine
+        .register_csv("progress".to_string(), "progress".to_string(), None)
+        .await
+        .unwrap();
+
+    let reports = Rc::new(RefCell::new(Vec::new()));
+    let collected = Rc::clone(&reports);
+    let on_progress =
+        Closure::<dyn FnMut(JsValue)>::new(move |progress| collected.borrow_mut().push(progress));
+    let options = js_sys::Object::new();
+    js_sys::Reflect::set(
+        &options,
+        &JsValue::from_str("onProgress"),
+        on_progress.as_ref(),
+    )
+    .unwrap();
+    engine
+        .run_sql("SELECT a FROM progress".to_string(), Some(options))
+        .await
+        .unwrap();
+
+    let last = reports.borrow().last().cloned().unwrap();
+    let field = |name: &str| js_sys::Reflect::get(&last, &JsValue::from_str(name)).unwrap();
+    assert_eq!(field("done"), JsValue::TRUE);
+    assert_eq!(field("rowsScanned").as_f64(), Some(3.0));
+    // "a\n1\n2\n3\n", counted for this query only
+    assert_eq!(field("bytesRead").as_f64(), Some(8.0));
+    assert!(field("batches").as_f64().unwrap() >= 1.0);
+}
+
+#[wasm_bindgen_test]
+async fn aggregates_report_progress_while_running() {
+    use std::{cell::RefCell, rc::Rc};
+
+    // large enough for the scan to outlast the report interval
+    let rows = 2_000_000;
+    let mut csv = String::from("a\n");
+    for i in 0..rows {
+        csv.push_str(&format!("{i}\n"));
+    }
+    OpfsFileSystem::new()
+        .put(&Path::from("progress_large.csv"), csv.clone().into())
+        .await
+        .unwrap();
+    let engine = QueryEngine::new();
+    engine
+        .register_csv(
+            "progress_large".to_string(),
+            "progress_large".to_string(),
+            None,
+        )
+        .await
+        .unwrap();
+
+    let reports = Rc::new(RefCell::new(Vec::new()));
+    let collected = Rc::clone(&reports);
+    let on_progress =
+        Closure::<dyn FnMut(JsValue)>::new(move |progress| collected.borrow_mut().push(progress));
+    let options = js_sys::Object::new();
+    js_sys::Reflect::set(
+        &options,
+        &JsValue::from_str("onProgress"),
+        on_progress.as_ref(),
+    )
+    .unwrap();
+    engine
+        .run_sql(
+            "SELECT count(*), sum(a) FROM progress_large".to_string(),
+            Some(options),
+        )
+        .await
+        .unwrap();
+
+    let reports = reports.borrow();
+    let field = |report: &JsValue, name: &str| {
+        js_sys::Reflect::get(report, &JsValue::from_str(name)).unwrap()
+    };
+    // the aggregate returns a single batch at the end, the reports before come from the timer
+    assert!(reports.len() >= 2, "{:?}", reports.len());
+    assert_eq!(field(&reports[0], "done"), JsValue::FALSE);
+    assert_eq!(field(&reports[0], "batches").as_f64(), Some(0.0));
+    let last = reports.last().unwrap();
+    assert_eq!(field(last, "done"), JsValue::TRUE);
+    assert_eq!(field(last, "rowsScanned").as_f64(), Some(rows as f64));
+    assert!(field(last, "bytesRead").as_f64().unwrap() >= csv.len() as f64);
+}
+
+#[wasm_bindgen_test]
+async fn explain_returns_plan_trees() {
+    let get = |value: &JsValue, name: &str| {
+        js_sys::Reflect::get(value, &JsValue::from_str(name)).unwrap()
+    };
+    let options = js_sys::Object::new();
+    js_sys::Reflect::set(&options, &JsValue::from_str("analyze"), &JsValue::TRUE).unwrap();
+    let explanation = explain_sql(
+        "SELECT a, count(*) FROM (VALUES (1), (1), (2)) AS t(a) GROUP BY a".to_string(),
+        options.into(),
+    )
+    .await
+    .unwrap();
+
+    let logical = get(&explanation, "logical");
+    assert!(!get(&logical, "operator").as_string().unwrap().is_empty());
+    let physical = get(&explanation, "physical");
+    assert!(get(&physical, "operator")
+        .as_string()
+        .unwrap()
+        .ends_with("Exec"));
+    assert_eq!(get(&physical, "actualRows").as_f64(), Some(2.0));
+    assert!(js_sys::Array::is_array(&get(&physical, "children")));
+    // every node of the analyzed plan has its own output bytes
+    let mut nodes = vec![physical];
+    let mut expressions = Vec::new();
+    while let Some(node) = nodes.pop() {
+        assert!(get(&node, "outputBytes").as_f64().unwrap() > 0.0);
+        for expression in js_sys::Array::from(&get(&node, "expressions")).iter() {
+            expressions.push(expression.as_string().unwrap());
+        }
+        nodes.extend(js_sys::Array::from(&get(&node, "children")).iter());
+    }
+    assert!(
+        expressions.iter().any(|e| e.contains("count")),
+        "{:?}",
+        expressions
+    );
+
+    let explanation = explain_sql("SELECT 1".to_string(), JsValue::UNDEFINED)
+        .await
+        .unwrap();
+    assert!(get(&get(&explanation, "physical"), "actualRows").is_null());
+}
+
+#[wasm_bindgen_test]
+async fn prepared_statements_bind_parameters() {
+    let stmt = prepare(
+        "SELECT a, b FROM (VALUES (1, 'one'), (2, 'two')) AS t(a, b) WHERE a = $1 OR b = $2"
+            .to_string(),
+    )
+    .await
+    .unwrap();
+    for (a, b) in [(1.0, "none"), (0.0, "two")] {
+        let params = js_sys::Array::of2(&JsValue::from(a), &JsValue::from_str(b));
+        let results = read_ipc(&execute(&stmt, params.into(), None).await.unwrap());
+        assert_eq!(
+            results.iter().map(|batch| batch.num_rows()).sum::<usize>(),
+            1
+        );
+    }
+
+    let engine = QueryEngine::new();
+    let stmt = engine
+        .prepare("SELECT $name AS name, $flag AS flag".to_string())
+        .await
+        .unwrap();
+    let params = js_sys::Object::new();
+    js_sys::Reflect::set(
+        &params,
+        &JsValue::from_str("name"),
+        &JsValue::from_str("x'; DROP TABLE t; --"),
+    )
+    .unwrap();
+    js_sys::Reflect::set(&params, &JsValue::from_str("flag"), &JsValue::TRUE).unwrap();
+    let results = read_ipc(&stmt.execute(params.into(), None).await.unwrap());
+    datafusion::assert_batches_eq!(
+        [
+            "+----------------------+------+",
+            "| name                 | flag |",
+            "+----------------------+------+",
+            "| x'; DROP TABLE t; -- | true |",
+            "+----------------------+------+",
+        ],
+        &results
+    );
+}
+
+#[wasm_bindgen_test]
+async fn table_schema_is_structured() {
+    let get = |value: &JsValue, name: &str| {
+        js_sys::Reflect::get(value, &JsValue::from_str(name)).unwrap()
+    };
+    let engine = QueryEngine::new();
+    engine
+        .run_sql(
+            "CREATE TABLE quoted AS SELECT 1 AS \"a\"\"b\", named_struct('x', 'y') AS s"
+                .to_string(),
+            None,
+        )
+        .await
+        .unwrap();
+
+    let schema = engine.get_table_schema("quoted".to_string()).await.unwrap();
+    assert_eq!(get(&schema, "name").as_string().unwrap(), "quoted");
+    let fields = js_sys::Array::from(&get(&schema, "fields"));
+    assert_eq!(fields.length(), 2);
+    let first = fields.get(0);
+    assert_eq!(get(&first, "name").as_string().unwrap(), "a\"b");
+    assert_eq!(get(&first, "dataType").as_string().unwrap(), "Int64");
+    assert_eq!(get(&first, "nullable"), JsValue::FALSE);
+    let children = js_sys::Array::from(&get(&fields.get(1), "children"));
+    assert_eq!(get(&children.get(0), "name").as_string().unwrap(), "x");
+
+    let completions = engine
+        .get_table_completions("quoted".to_string())
+        .await
+        .unwrap();
+    let completions = js_sys::JSON::parse(&completions.as_string().unwrap()).unwrap();
+    let columns = js_sys::Array::from(&get(&completions, "quoted"));
+    assert_eq!(get(&columns.get(0), "label").as_string().unwrap(), "a\"b");
+    assert_eq!(
+        get(&columns.get(0), "type").as_string().unwrap(),
+        "property"
+    );
+}
+
+#[wasm_bindgen_test]
+async fn catalog_lists_tables_and_functions() {
+    let get = |value: &JsValue, name: &str| {
+        js_sys::Reflect::get(value, &JsValue::from_str(name)).unwrap()
+    };
+    let engine = QueryEngine::new();
+    for sql in [
+        "COPY (SELECT 1 AS a) TO 'opfs:///catalog_file.csv' STORED AS CSV",
+        "CREATE TABLE in_memory AS VALUES (1)",
+        "CREATE VIEW a_view AS SELECT * FROM in_memory",
+    ] {
+        engine.run_sql(sql.to_string(), None).await.unwrap();
+    }
+    engine
+        .register_csv("catalog_file".to_string(), "from_file".to_string(), None)
+        .await
+        .unwrap();
+
+    assert_eq!(engine.list_catalogs(), vec!["datafusion".to_string()]);
+    let tables = js_sys::Array::from(
+        &engine
+            .list_tables(None, Some("public".to_string()))
+            .await
+            .unwrap(),
+    );
+    let described: Vec<(String, String)> = tables
+        .iter()
+        .map(|table| {
+            (
+                get(&table, "name").as_string().unwrap(),
+                get(&table, "tableType").as_string().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        described,
+        vec![
+            ("a_view".to_string(), "view".to_string()),
+            ("from_file".to_string(), "csv".to_string()),
+            ("in_memory".to_string(), "memory".to_string()),
+        ]
+    );
+    let paths = js_sys::Array::from(&get(&tables.get(1), "paths"));
+    assert_eq!(
+        paths.get(0).as_string().unwrap(),
+        "opfs:///catalog_file.csv"
+    );
+
+    let functions = js_sys::Array::from(&engine.list_functions().unwrap());
+    let sum = functions
+        .iter()
+        .find(|f| get(f, "name").as_string().unwrap() == "sum")
+        .unwrap();
+    assert_eq!(get(&sum, "kind").as_string().unwrap(), "aggregate");
+}
+
+#[wasm_bindgen_test]
+async fn workspace_catalog_survives_reopening() {
+    let engine = QueryEngine::open("persisted".to_string()).await.unwrap();
+    engine
+        .run_sql(
+            "COPY (SELECT 7 AS lucky, 'a,b' AS letters) TO 'opfs:///persisted_file.csv' \
+             STORED AS CSV OPTIONS ('format.delimiter' ';')"
+                .to_string(),
+            None,
+        )
+        .await
+        .unwrap();
+    let csv_config = js_sys::Object::new();
+    js_sys::Reflect::set(&csv_config, &"delimiter".into(), &";".into()).unwrap();
+    engine
+        .register_csv(
+            "persisted_file".to_string(),
+            "kept".to_string(),
+            Some(csv_config.into()),
+        )
+        .await
+        .unwrap();
+    drop(engine);
+
+    let engine = QueryEngine::open("persisted".to_string()).await.unwrap();
+    let result = engine
+        .run_sql("SELECT lucky, letters FROM kept".to_string(), None)
+        .await
+        .unwrap();
+    let results = read_ipc(&result);
+    datafusion::assert_batches_eq!(
+        [
+            "+-------+---------+",
+            "| lucky | letters |",
+            "+-------+---------+",
+            "| 7     | a,b     |",
+            "+-------+---------+"
+        ],
+        &results
+    );
+
+    engine.unregister_table("kept".to_string()).await.unwrap();
+    let engine = QueryEngine::open("persisted".to_string()).await.unwrap();
+    assert!(engine
+        .run_sql("SELECT lucky FROM kept".to_string(), None)
+        .await
+        .is_err());
+}
+
+#[wasm_bindgen_test]
+async fn workspace_catalog_is_shared_between_sessions() {
+    // like two tabs on the same workspace, both opened before either registers a table
+    let first = QueryEngine::open("shared".to_string()).await.unwrap();
+    let second = QueryEngine::open("shared".to_string()).await.unwrap();
+    first
+        .run_sql(
+            "COPY (SELECT 1 AS a) TO 'opfs:///shared_first.csv' STORED AS CSV".to_string(),
+            None,
+        )
+        .await
+        .unwrap();
+    first
+        .register_csv("shared_first".to_string(), "first".to_string(), None)
+        .await
+        .unwrap();
+    second
+        .run_sql(
+            "COPY (SELECT 2 AS b) TO 'opfs:///shared_second.csv' STORED AS CSV".to_string(),
+            None,
+        )
+        .await
+        .unwrap();
+    second
+        .register_csv("shared_second".to_string(), "second".to_string(), None)
+        .await
+        .unwrap();
+    // the first session's manifest is outdated now, its next change must keep "second"
+    first.unregister_table("first".to_string()).await.unwrap();
+    first
+        .register_csv("shared_first".to_string(), "first".to_string(), None)
+        .await
+        .unwrap();
+
+    let reopened = QueryEngine::open("shared".to_string()).await.unwrap();
+    let result = reopened
+        .run_sql("SELECT a, b FROM first CROSS JOIN second".to_string(), None)
+        .await
+        .unwrap();
+    let results = read_ipc(&result);
+    datafusion::assert_batches_eq!(
+        [
+            "+---+---+",
+            "| a | b |",
+            "+---+---+",
+            "| 1 | 2 |",
+            "+---+---+"
+        ],
+        &results
+    );
+}
+
+#[wasm_bindgen_test]
+async fn broken_workspace_catalog_is_set_aside() {
+    let window: Window = web_sys::window().unwrap();
+    let data = try_get_file_folder(&window).await.unwrap();
+    let options = &FileSystemGetDirectoryOptions::new();
+    options.set_create(true);
+    let catalog = try_get_from_promise::<FileSystemDirectoryHandle>(
+        data.get_directory_handle_with_options(".catalog", options),
+    )
+    .await
+    .unwrap();
+    let options = &FileSystemGetFileOptions::new();
+    options.set_create(true);
+    let manifest = try_get_from_promise::<FileSystemFileHandle>(
+        catalog.get_file_handle_with_options("broken.json", options),
+    )
+    .await
+    .unwrap();
+    let writable = try_get_from_promise::<FileSystemWritableFileStream>(manifest.create_writable())
+        .await
+        .unwrap();
+    try_get_from_promise::<JsValue>(writable.write_with_str("{\"version\": 1, \"tab").unwrap())
+        .await
+        .unwrap();
+    try_get_from_promise::<JsValue>(writable.close())
+        .await
+        .unwrap();
+
+    let engine = QueryEngine::open("broken".to_string()).await.unwrap();
+    assert!(engine.run_sql("SELECT 1".to_string(), None).await.is_ok());
+    // the broken file is kept for the user to look at
+    try_get_from_promise::<FileSystemFileHandle>(catalog.get_file_handle("broken.json.invalid"))
+        .await
+        .unwrap();
+}
+
+#[wasm_bindgen_test]
+async fn parquet_ingestion_and_export() {
+    let options = js_sys::JSON::parse(
+        r#"{"format": "parquet", "compression": "snappy", "row_group_size": 10}"#,
+    )
+    .unwrap();
+    persist_sql(
+        "SELECT value AS id, value % 3 AS bucket FROM generate_series(1, 25)".to_string(),
+        "exported".to_string(),
+        options,
+    )
+    .await
+    .unwrap();
+    register_parquet("exported".to_string(), "exported".to_string())
+        .await
+        .unwrap();
+
+    // a Parquet file written outside the engine, converted to Arrow IPC
+    let ids: ArrayRef = Arc::new(Int64Array::from_iter_values(1..=25));
+    let batch = RecordBatch::try_from_iter([("id", ids)]).unwrap();
+    let mut writer = ArrowWriter::try_new(Vec::new(), batch.schema(), None).unwrap();
+    writer.write(&batch).unwrap();
+    let bytes = writer.into_inner().unwrap();
+    load_parquet_bytes(
+        Uint8Array::from(&bytes[..]).buffer(),
+        "imported".to_string(),
+    )
+    .await
+    .unwrap();
+    register_table("imported".to_string(), "imported".to_string())
+        .await
+        .unwrap();
+
+    let result = run_sql(
+        "SELECT bucket, count(*) AS n, sum(id) AS total FROM exported GROUP BY bucket \
+         UNION ALL SELECT id % 3 + 10, count(*), sum(id) FROM imported GROUP BY id % 3 \
+         ORDER BY bucket"
+            .to_string(),
+        None,
+    )
+    .await
+    .unwrap();
+    let arr_vec: Vec<u8> = Uint8Array::new(&result).to_vec();
+    let reader = StreamReader::try_new(&arr_vec[..], None).unwrap();
+    let results: Vec<RecordBatch> = reader.map(|batch| batch.unwrap()).collect();
+    datafusion::assert_batches_eq!(
+        [
+            "+--------+---+-------+",
+            "| bucket | n | total |",
+            "+--------+---+-------+",
+            "| 0      | 8 | 108   |",
+            "| 1      | 9 | 117   |",
+            "| 2      | 8 | 100   |",
+            "| 10     | 8 | 108   |",
+            "| 11     | 9 | 117   |",
+            "| 12     | 8 | 100   |",
+            "+--------+---+-------+",
+        ],
+        &results
+    );
+
+    // gzip needs a level
+    let invalid = js_sys::JSON::parse(r#"{"format": "parquet", "compression": "gzip"}"#).unwrap();
+    assert!(
+        persist_sql("SELECT 1".to_string(), "invalid".to_string(), invalid)
+            .await
+            .is_err()
+    );
+}
+
+#[wasm_bindgen_test]
+async fn json_ingestion() {
+    // starts with a byte order mark, as files saved by some editors do
+    let array = concat!(
+        "\u{FEFF}",
+        r#"[{"id": 1, "tags": ["a", "b"], "owner": {"name": "x"}}, {"id": 2, "tags": []}]"#
+    );
+    let ndjson = "{\"id\": 3, \"tags\": [\"c\"]}\n{\"id\": 4, \"owner\": {\"name\": \"y\"}}\n";
+    for (digest, json) in [("json_array", array), ("json_lines", ndjson)] {
+        load_json_bytes(
+            Uint8Array::from(json.as_bytes()).buffer(),
+            digest.to_string(),
+        )
+        .await
+        .unwrap();
+        register_table(digest.to_string(), digest.to_string())
+            .await
+            .unwrap();
+    }
+
+    let results = read_ipc(
+        &run_sql(
+            "SELECT id, array_length(tags) AS tags, owner['name'] AS owner FROM json_array \
+             UNION ALL SELECT id, array_length(tags), owner['name'] FROM json_lines ORDER BY id"
+                .to_string(),
+            None,
+        )
+        .await
+        .unwrap(),
+    );
+    datafusion::assert_batches_eq!(
+        [
+            "+----+------+-------+",
+            "| id | tags | owner |",
+            "+----+------+-------+",
+            "| 1  | 2    | x     |",
+            "| 2  | 0    |       |",
+            "| 3  | 1    |       |",
+            "| 4  |      | y     |",
+            "+----+------+-------+",
+        ],
+        &results
+    );
+
+    run_sql(
+        "COPY (SELECT 5 AS id) TO 'opfs:///direct.json' STORED AS JSON".to_string(),
+        None,
+    )
+    .await
+    .unwrap();
+    register_json("direct".to_string(), "direct".to_string())
+        .await
+        .unwrap();
+    let results = read_ipc(
+        &run_sql("SELECT id FROM direct".to_string(), None)
+            .await
+            .unwrap(),
+    );
+    assert_eq!(results[0].num_rows(), 1);
+}
+
+#[wasm_bindgen_test]
+async fn csv_files_are_imported_in_chunks() {
+    // larger than one chunk, so rows are split across chunk boundaries
+    let rows = 500_000;
+    let mut csv = String::from("id,double\n");
+    for i in 0..rows {
+        csv.push_str(&format!("{i},{}\n", i * 2));
+    }
+    let parts = js_sys::Array::of1(&JsValue::from_str(&csv));
+    let blob = web_sys::Blob::new_with_str_sequence(&parts).unwrap();
+    let config = js_sys::JSON::parse(
+        r#"{"delimiter": ",", "quote": "", "comment": "", "escape": "", "null_regex": "", "truncated": false}"#,
+    )
+    .unwrap();
+    load_csv_file(blob, "chunked".to_string(), config)
+        .await
+        .unwrap();
+    register_table("chunked".to_string(), "chunked".to_string())
+        .await
+        .unwrap();
+
+    let result = run_sql(
+        "SELECT count(*) AS n, sum(double - 2 * id) AS diff FROM chunked".to_string(),
+        None,
+    )
+    .await
+    .unwrap();
+    let results = read_ipc(&result);
+    datafusion::assert_batches_eq!(
+        [
+            "+--------+------+",
+            "| n      | diff |",
+            "+--------+------+",
+            "| 500000 | 0    |",
+            "+--------+------+"
+        ],
+        &results
+    );
+}
+
+#[wasm_bindgen_test]
+async fn csv_config_controls_schema() {
+    let csv = "1;03.02.2024;03.02.2024 14:30\n2;;\n3;29.02.2024;29.02.2024 08:00\n";
+    let parts = js_sys::Array::of1(&JsValue::from_str(csv));
+    let blob = web_sys::Blob::new_with_str_sequence(&parts).unwrap();
+    let config = js_sys::JSON::parse(
+        r#"{"delimiter": ";", "quote": "", "comment": "", "escape": "", "null_regex": "", "truncated": false,
+            "has_header": false,
+            "schema": [{"name": "id", "type": "int"}, {"name": "day", "type": "date"}, {"name": "at", "type": "timestamp"}],
+            "date_format": "%d.%m.%Y", "timestamp_format": "%d.%m.%Y %H:%M"}"#,
+    )
+    .unwrap();
+    let schema = load_csv_file(blob, "formatted".to_string(), config)
+        .await
+        .unwrap();
+    let types = js_sys::JSON::stringify(&schema)
+        .unwrap()
+        .as_string()
+        .unwrap();
+    assert!(types.contains(r#""dataType":"Date32""#), "{}", types);
+    assert!(
+        types.contains(r#""dataType":"Timestamp(Millisecond, None)""#),
+        "{}",
+        types
+    );
+    register_table("formatted".to_string(), "formatted".to_string())
+        .await
+        .unwrap();
+
+    let result = run_sql(
+        "SELECT id, day, at FROM formatted ORDER BY id".to_string(),
+        None,
+    )
+    .await
+    .unwrap();
+    let results = read_ipc(&result);
+    datafusion::assert_batches_eq!(
+        [
+            "+----+------------+---------------------+",
+            "| id | day        | at                  |",
+            "+----+------------+---------------------+",
+            "| 1  | 2024-02-03 | 2024-02-03T14:30:00 |",
+            "| 2  |            |                     |",
+            "| 3  | 2024-02-29 | 2024-02-29T08:00:00 |",
+            "+----+------------+---------------------+",
+        ],
+        &results
+    );
+
+    // the last row is past the default sample and turns the integers into floats
+    let csv = format!("v\n{}1.5\n", "1\n".repeat(1500));
+    let bytes = Uint8Array::from(csv.as_bytes());
+    let config = js_sys::JSON::parse(
+        r#"{"delimiter": ",", "quote": "", "comment": "", "escape": "", "null_regex": "", "truncated": false,
+            "infer_rows": "all", "column_types": {}}"#,
+    )
+    .unwrap();
+    let schema = load_csv_bytes(bytes.buffer(), "widened".to_string(), config)
+        .await
+        .unwrap();
+    let types = js_sys::JSON::stringify(&schema)
+        .unwrap()
+        .as_string()
+        .unwrap();
+    assert!(types.contains(r#""dataType":"Float64""#), "{}", types);
+
+    let config = js_sys::JSON::parse(
+        r#"{"delimiter": ",", "quote": "", "comment": "", "escape": "", "null_regex": "", "truncated": false,
+            "column_types": {"missing": "int"}}"#,
+    )
+    .unwrap();
+    let error = load_csv_bytes(bytes.buffer(), "overridden".to_string(), config)
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), "schema");
+}
+
+#[wasm_bindgen_test]
+async fn csv_preview_reports_errors() {
+    let bytes = Uint8Array::from(&b"id,amount\n1,2.5\n2,abc\n3,\n4,1\n"[..]);
+    let config = js_sys::JSON::parse(
+        r#"{"delimiter": ",", "quote": "", "comment": "", "escape": "", "null_regex": "", "truncated": false,
+            "column_types": {"amount": "float"}}"#,
+    )
+    .unwrap();
+    let preview = preview_csv(bytes.buffer(), config, 3).await.unwrap();
+
+    let rows = js_sys::Reflect::get(&preview, &JsValue::from_str("rows")).unwrap();
+    let results = read_ipc(&rows);
+    datafusion::assert_batches_eq!(
+        [
+            "+----+--------+",
+            "| id | amount |",
+            "+----+--------+",
+            "| 1  | 2.5    |",
+            "| 2  |        |",
+            "| 3  |        |",
+            "+----+--------+"
+        ],
+        &results
+    );
+
+    let null_counts = js_sys::Reflect::get(&preview, &JsValue::from_str("nullCounts")).unwrap();
+    assert_eq!(
+        js_sys::JSON::stringify(&null_counts)
+            .unwrap()
+            .as_string()
+            .unwrap(),
+        r#"{"amount":1,"id":0}"#
+    );
+    let errors = js_sys::Reflect::get(&preview, &JsValue::from_str("errors")).unwrap();
+    assert_eq!(
+        js_sys::JSON::stringify(&errors)
+            .unwrap()
+            .as_string()
+            .unwrap(),
+        r#"[{"row":1,"column":"amount","value":"abc","message":"Cannot parse \"abc\" as Float64"}]"#
+    );
+}
+
+#[wasm_bindgen_test]
+async fn utf16_characters_span_chunks() {
+    // the byte order mark and header take 8 bytes, so the surrogate pair of row 699049
+    // starts 2 bytes before the end of the first 4 MiB chunk
+    let rows = 700_000;
+    let mut csv = String::from("ss\n");
+    for _ in 0..rows {
+        csv.push_str("\u{1f600}\n");
+    }
+    let mut bytes = vec![0xff, 0xfe];
+    bytes.extend(csv.encode_utf16().flat_map(u16::to_le_bytes));
+    let bytes = Uint8Array::from(&bytes[..]);
+
+    let config = js_sys::JSON::parse(r#"{"encoding": "utf-16le", "has_header": true}"#).unwrap();
+    load_csv_bytes(bytes.buffer(), "utf16".to_string(), config)
+        .await
+        .unwrap();
+    register_table("utf16".to_string(), "utf16".to_string())
+        .await
+        .unwrap();
+
+    let result = run_sql(
+        "SELECT count(*) AS n, count(DISTINCT ss) AS distinct_ss FROM utf16".to_string(),
+        None,
+    )
+    .await
+    .unwrap();
+    let results = read_ipc(&result);
+    datafusion::assert_batches_eq!(
+        [
+            "+--------+-------------+",
+            "| n      | distinct_ss |",
+            "+--------+-------------+",
+            "| 700000 | 1           |",
+            "+--------+-------------+",
+        ],
+        &results
+    );
+}
+
+#[wasm_bindgen_test]
+async fn csv_dialect_is_sniffed() {
+    // windows-1252 with a decimal comma and empty columns at the end of every row
+    let mut csv = b"ort;temperatur;menge;;;\r\nZ".to_vec();
+    csv.push(0xfc);
+    csv.extend_from_slice(b"rich;1,5;1.200;;;\r\nBern;-2,25;3;;;\r\n");
+    let bytes = Uint8Array::from(&csv[..]);
+
+    let dialect = sniff_csv(bytes.buffer()).unwrap();
+    assert_eq!(
+        js_sys::JSON::stringify(&dialect)
+            .unwrap()
+            .as_string()
+            .unwrap(),
+        r#"{"encoding":"windows-1252","delimiter":";","quote":"\"","hasHeader":true,"decimal":",","columns":6,"trailingEmptyColumns":3}"#
+    );
+
+    // every other field left out is taken from the sniffed dialect
+    let config = js_sys::JSON::parse(r#"{"trim_empty_columns": true}"#).unwrap();
+    load_csv_bytes(bytes.buffer(), "sniffed".to_string(), config)
+        .await
+        .unwrap();
+    register_table("sniffed".to_string(), "sniffed".to_string())
+        .await
+        .unwrap();
+    let result = run_sql("SELECT * FROM sniffed ORDER BY menge".to_string(), None)
+        .await
+        .unwrap();
+    let results = read_ipc(&result);
+    datafusion::assert_batches_eq!(
+        [
+            "+--------+------------+--------+",
+            "| ort    | temperatur | menge  |",
+            "+--------+------------+--------+",
+            "| Bern   | -2.25      | 3.0    |",
+            "| Zürich | 1.5        | 1200.0 |",
+            "+--------+------------+--------+",
+        ],
+        &results
+    );
+
+    // a trimmed column holding a value past the 100 sniffed rows fails the import
+    let mut csv = b"ort;menge;\n".to_vec();
+    for i in 0..200 {
+        csv.extend_from_slice(format!("ort{i};{i};\n").as_bytes());
+    }
+    csv.extend_from_slice(b"Bern;3;Notiz\n");
+    let bytes = Uint8Array::from(&csv[..]);
+    let config = js_sys::JSON::parse(r#"{"trim_empty_columns": true}"#).unwrap();
+    assert!(
+        load_csv_bytes(bytes.buffer(), "trimmed".to_string(), config)
+            .await
+            .is_err()
+    );
+}
+
+#[wasm_bindgen_test]
+async fn csv_errors_are_rejected() {
+    let csv = "id,amount\n1,2.5\n2,abc\n3\n4,1,extra\n5,7\n";
+    let config = |on_error: &str| {
+        js_sys::JSON::parse(&format!(
+            r#"{{"column_types": {{"amount": "float"}}, "on_error": "{on_error}", "max_errors": 3}}"#
+        ))
+        .unwrap()
+    };
+
+    let bytes = Uint8Array::from(csv.as_bytes());
+    let error = load_csv_bytes(bytes.buffer(), "strict".to_string(), config("fail"))
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), "schema");
+
+    let import = load_csv_bytes(bytes.buffer(), "skipped".to_string(), config("skip"))
+        .await
+        .unwrap();
+    let rejected_table =
+        js_sys::Reflect::get(&import, &JsValue::from_str("rejectedTable")).unwrap();
+    assert_eq!(rejected_table.as_string().unwrap(), "skipped.rejected");
+    register_table("skipped".to_string(), "skipped".to_string())
+        .await
+        .unwrap();
+    register_table(
+        "skipped.rejected".to_string(),
+        "skipped_rejected".to_string(),
+    )
+    .await
+    .unwrap();
+    let result = run_sql(
+        "SELECT line, \"column\", value, reason FROM skipped_rejected ORDER BY line".to_string(),
+        None,
+    )
+    .await
+    .unwrap();
+    let results = read_ipc(&result);
+    datafusion::assert_batches_eq!(
+        [
+            "+------+--------+-------+-------------------------------+",
+            "| line | column | value | reason                        |",
+            "+------+--------+-------+-------------------------------+",
+            "| 3    | amount | abc   | Cannot parse \"abc\" as Float64 |",
+            "| 4    |        |       | Expected 2 fields, found 1    |",
+            "| 5    |        |       | Expected 2 fields, found 3    |",
+            "+------+--------+-------+-------------------------------+",
+        ],
+        &results
+    );
+    let result = run_sql(
+        "SELECT id, amount FROM skipped ORDER BY id".to_string(),
+        None,
+    )
+    .await
+    .unwrap();
+    let results = read_ipc(&result);
+    datafusion::assert_batches_eq!(
+        [
+            "+----+--------+",
+            "| id | amount |",
+            "+----+--------+",
+            "| 1  | 2.5    |",
+            "| 5  | 7.0    |",
+            "+----+--------+"
+        ],
+        &results
+    );
+
+    let import = load_csv_bytes(bytes.buffer(), "filled".to_string(), config("null_fill"))
+        .await
+        .unwrap();
+    assert_eq!(
+        js_sys::Reflect::get(&import, &JsValue::from_str("rows"))
+            .unwrap()
+            .as_f64(),
+        Some(5.0)
+    );
+    register_table("filled".to_string(), "filled".to_string())
+        .await
+        .unwrap();
+    let result = run_sql(
+        "SELECT id, amount FROM filled ORDER BY id".to_string(),
+        None,
+    )
+    .await
+    .unwrap();
+    let results = read_ipc(&result);
+    datafusion::assert_batches_eq!(
+        [
+            "+----+--------+",
+            "| id | amount |",
+            "+----+--------+",
+            "| 1  | 2.5    |",
+            "| 2  |        |",
+            "| 3  |        |",
+            "| 4  | 1.0    |",
+            "| 5  | 7.0    |",
+            "+----+--------+",
+        ],
+        &results
+    );
+
+    // the rejected rows are kept when the import gives up
+    let config = js_sys::JSON::parse(
+        r#"{"column_types": {"amount": "float"}, "on_error": "skip", "max_errors": 1}"#,
+    )
+    .unwrap();
+    let error = load_csv_bytes(bytes.buffer(), "aborted".to_string(), config)
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), "schema");
+    register_table(
+        "aborted.rejected".to_string(),
+        "aborted_rejected".to_string(),
+    )
+    .await
+    .unwrap();
+    register_table("aborted".to_string(), "aborted".to_string())
+        .await
+        .unwrap_err();
+}
+
+#[wasm_bindgen_test]
+async fn csv_errors_keep_quotes_inside_fields() {
+    // quotes that do not start a field are part of the value
+    let csv = "size,item\n5'11\",x\n12\" pipe,y,extra\n\"a,b\",z\n";
+    let config = js_sys::JSON::parse(r#"{"on_error": "skip"}"#).unwrap();
+    let bytes = Uint8Array::from(csv.as_bytes());
+    load_csv_bytes(bytes.buffer(), "quotes".to_string(), config)
+        .await
+        .unwrap();
+    register_table("quotes".to_string(), "quotes".to_string())
+        .await
+        .unwrap();
+    register_table("quotes.rejected".to_string(), "quotes_rejected".to_string())
+        .await
+        .unwrap();
+    let result = run_sql(
+        "SELECT size, item FROM quotes ORDER BY item".to_string(),
+        None,
+    )
+    .await
+    .unwrap();
+    let results = read_ipc(&result);
+    datafusion::assert_batches_eq!(
+        [
+            "+-------+------+",
+            "| size  | item |",
+            "+-------+------+",
+            "| 5'11\" | x    |",
+            "| a,b   | z    |",
+            "+-------+------+",
+        ],
+        &results
+    );
+    let result = run_sql("SELECT line, reason FROM quotes_rejected".to_string(), None)
+        .await
+        .unwrap();
+    let results = read_ipc(&result);
+    datafusion::assert_batches_eq!(
+        [
+            "+------+----------------------------+",
+            "| line | reason                     |",
+            "+------+----------------------------+",
+            "| 3    | Expected 2 fields, found 3 |",
+            "+------+----------------------------+",
+        ],
+        &results
+    );
+}